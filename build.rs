@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, path::Path};
 
 type Result<T = ()> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -22,8 +22,11 @@ macro_rules! env {
 fn main() -> Result {
     let manifest_dir = env::var("CARGO_MANIFEST_DIR")?;
     let profile = env::var("PROFILE")?;
-    if profile.as_str() == "debug" {
-        env!(IMAGE = format!("{manifest_dir}/res/raspbian.img"));
+    let image = format!("{manifest_dir}/res/raspbian.img");
+    // Optional so debug builds work without the image in the source tree,
+    // pi image --image can be used instead
+    if profile.as_str() == "debug" && Path::new(&image).exists() {
+        env!(IMAGE = image);
     }
     Ok(())
 }
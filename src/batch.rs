@@ -0,0 +1,203 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    thread::{self, ScopedJoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::device::Device;
+
+// How often sysfs is checked for newly inserted cards
+const POLL: Duration = Duration::from_secs(1);
+
+/// The hostnames for a pattern with a numeric range in braces, as in
+/// `sensor-{01..20}`, None if `pattern` is a plain hostname
+///
+/// A leading zero on the start of the range pads every number to its width
+pub(crate) fn expand(pattern: &str) -> Result<Option<Vec<String>>> {
+    let Some((prefix, rest)) = pattern.split_once('{') else {
+        return Ok(None);
+    };
+    let invalid = || {
+        anyhow!(
+            "Invalid hostname pattern {pattern} (as in sensor-{{01..20}}, \
+             with a single range)"
+        )
+    };
+    let (range, suffix) = rest.split_once('}').ok_or_else(invalid)?;
+    if [prefix, suffix]
+        .iter()
+        .any(|part| part.contains(['{', '}']))
+    {
+        return Err(invalid());
+    }
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let first = start.parse::<u32>().map_err(|_| invalid())?;
+    let last = end.parse::<u32>().map_err(|_| invalid())?;
+    if first > last {
+        bail!("The range in {pattern} counts down")
+    }
+    let width = if start.len() > 1 && start.starts_with('0') {
+        start.len()
+    } else {
+        0
+    };
+    let names = (first..=last)
+        .map(|n| format!("{prefix}{n:0width$}{suffix}"))
+        .collect::<Vec<_>>();
+    for name in &names {
+        validate_hostname(name)?;
+    }
+    Ok(Some(names))
+}
+
+/// Check `name` is a hostname (an RFC 1123 label), which is also what the pi
+/// is known by locally (its identity, overlay, inventory entry, ...)
+pub(crate) fn validate_hostname(name: &str) -> Result<()> {
+    let valid = (1..=63).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    if !valid {
+        bail!(
+            "Invalid hostname {name} (up to 63 letters, digits and -, not \
+             starting or ending with -)"
+        )
+    }
+    Ok(())
+}
+
+/// Wait for cards to be inserted, calling `image` on its own thread for each
+/// with the next of `names`, until every name has been used
+///
+/// Cards already inserted are left alone, they might not be meant for
+/// imaging. A name whose card fails goes to the next card inserted
+pub(crate) fn run(
+    names: Vec<String>,
+    image: impl Fn(&str, &Device) -> Result<()> + Sync,
+) -> Result<()> {
+    let total = names.len();
+    let mut names = VecDeque::from(names);
+    let mut present = Device::enumerate()?
+        .into_iter()
+        .map(|device| {
+            println!(
+                "Ignoring {device}, it was inserted before starting (reinsert \
+                 it to image it)"
+            );
+            device.name
+        })
+        .collect::<HashSet<_>>();
+    println!("Insert cards to image ({total} to go), Ctrl-C to stop");
+
+    let image = &image;
+    thread::scope(|scope| {
+        let mut busy = Vec::<Card<'_>>::new();
+        let mut done = 0;
+        loop {
+            let finished = busy
+                .extract_if(.., |card| card.handle.is_finished())
+                .collect::<Vec<_>>();
+            for Card {
+                name,
+                device,
+                handle,
+            } in finished
+            {
+                let result = handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e));
+                match result {
+                    Ok(()) => {
+                        done += 1;
+                        println!(
+                            "[{name}] Done ({done}/{total}), {} can be removed",
+                            device.path.display()
+                        );
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "[{name}] Failed: {e:#}, the next card inserted \
+                             will get this name"
+                        );
+                        names.push_front(name);
+                    }
+                }
+            }
+            if names.is_empty() && busy.is_empty() {
+                break;
+            }
+
+            let devices = Device::enumerate()?;
+            // Forgetting removed cards lets a reinserted one be imaged again
+            present.retain(|name| {
+                devices.iter().any(|device| device.name == *name)
+                    || busy.iter().any(|card| card.device.name == *name)
+            });
+            for device in devices {
+                if !present.insert(device.name.clone()) {
+                    continue;
+                }
+                let Some(name) = names.pop_front() else {
+                    println!("Ignoring {device}, every name is taken");
+                    continue;
+                };
+                if let Err(e) = device.ensure_unused() {
+                    eprintln!("Ignoring {device}: {e:#}");
+                    names.push_front(name);
+                    continue;
+                }
+                println!("[{name}] Imaging {device}...");
+                let handle = {
+                    let (name, device) = (name.clone(), device.clone());
+                    scope.spawn(move || image(&name, &device))
+                };
+                busy.push(Card {
+                    name,
+                    device,
+                    handle,
+                });
+            }
+            thread::sleep(POLL);
+        }
+        println!("Imaged {total} cards");
+        Ok(())
+    })
+}
+
+/// A card being imaged
+struct Card<'scope> {
+    name: String,
+    device: Device,
+    handle: ScopedJoinHandle<'scope, Result<()>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames() {
+        for name in ["pi", "sensor-01", "4b", &"a".repeat(63)] {
+            validate_hostname(name).unwrap();
+        }
+        for name in ["", "-pi", "pi-", "../x", "a/b", "pi.lan", "pi_1"] {
+            assert!(validate_hostname(name).is_err(), "{name}");
+        }
+        assert!(validate_hostname(&"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(expand("pi").unwrap(), None);
+        assert_eq!(
+            expand("sensor-{08..10}").unwrap().unwrap(),
+            ["sensor-08", "sensor-09", "sensor-10"]
+        );
+        assert_eq!(expand("{1..2}b").unwrap().unwrap(), ["1b", "2b"]);
+        assert!(expand("sensor-{3..1}").is_err());
+        assert!(expand("sensor_{1..3}").is_err());
+        assert!(expand("../{1..3}").is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use std::{fmt, fs, process::Command, str::FromStr};
+
+use anyhow::{Context as _, Error, Result, bail};
+use argh::FromArgs;
+use command_ext::CommandExt as _;
+use serde::Deserialize;
+
+use crate::{
+    CommandExt as _, cat, push, utils,
+    volume::{Mode, Volume},
+};
+
+const CONFIG_TXT: &str = "config.txt";
+const CMDLINE_TXT: &str = "cmdline.txt";
+const CMDLINE_PREFIX: &str = "cmdline:";
+// Where the boot partition is mounted since Bookworm, and before it
+const FIRMWARE: &str = "/boot/firmware";
+const LEGACY_FIRMWARE: &str = "/boot";
+
+/// Show or change config.txt and cmdline.txt on a running pi
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "boot-config")]
+pub(crate) struct Args {
+    /// the pi to configure
+    #[argh(positional)]
+    name: String,
+    /// a config.txt setting (e.g. dtparam=audio=on), or a kernel argument
+    /// prefixed with cmdline:, can be repeated
+    #[argh(option)]
+    set: Vec<Setting>,
+    /// a setting to remove, dtoverlay and dtparam are matched by name and
+    /// everything else by key, can be repeated
+    #[argh(option)]
+    unset: Vec<Setting>,
+}
+
+/// The boot file a setting belongs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum File {
+    Config,
+    Cmdline,
+}
+
+impl File {
+    fn name(self) -> &'static str {
+        match self {
+            File::Config => CONFIG_TXT,
+            File::Cmdline => CMDLINE_TXT,
+        }
+    }
+}
+
+/// A `key=value` line for config.txt, or a kernel argument for cmdline.txt
+/// when prefixed with `cmdline:`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Setting {
+    file: File,
+    key: String,
+    value: Option<String>,
+}
+
+impl Setting {
+    /// A kernel argument for cmdline.txt
+    pub(crate) fn cmdline(key: &str, value: Option<&str>) -> Result<Self> {
+        Self::new(File::Cmdline, key, value)
+    }
+
+    fn new(file: File, key: &str, value: Option<&str>) -> Result<Self> {
+        let setting = Self {
+            file,
+            key: key.to_owned(),
+            value: value.map(str::to_owned),
+        };
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            bail!("Invalid boot setting {setting}")
+        }
+        if file == File::Config && key.starts_with(['#', '[']) {
+            bail!("Invalid config.txt setting {setting}")
+        }
+        // The kernel splits its arguments on spaces, and config.txt has a
+        // setting per line
+        let invalid = match file {
+            File::Cmdline => char::is_whitespace,
+            File::Config => char::is_control,
+        };
+        if value.is_some_and(|value| value.contains(invalid)) {
+            bail!("Invalid value in boot setting {setting:?}")
+        }
+        Ok(setting)
+    }
+
+    /// The setting as it's written in its file
+    fn arg(&self) -> String {
+        match &self.value {
+            Some(value) => format!("{}={value}", self.key),
+            None => self.key.clone(),
+        }
+    }
+
+    /// Whether `key` and `value` are set by this, so setting this replaces
+    /// them
+    ///
+    /// config.txt can load any number of overlays and set any number of
+    /// parameters, so those are told apart by name rather than key
+    fn matches(&self, key: &str, value: Option<&str>) -> bool {
+        fn name(value: &str) -> &str {
+            value.split([',', '=']).next().unwrap_or(value)
+        }
+
+        if self.key != key {
+            return false;
+        }
+        match (self.file, key, &self.value, value) {
+            (
+                File::Config,
+                "dtoverlay" | "dtparam",
+                Some(mine),
+                Some(theirs),
+            ) => name(mine) == name(theirs),
+            (File::Config, "dtoverlay" | "dtparam", _, _) => false,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for Setting {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (file, setting) = match s.strip_prefix(CMDLINE_PREFIX) {
+            Some(setting) => (File::Cmdline, setting),
+            None => (File::Config, s),
+        };
+        let (key, value) = match setting.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (setting, None),
+        };
+        Self::new(file, key, value)
+    }
+}
+
+impl TryFrom<String> for Setting {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.file == File::Cmdline {
+            write!(f, "{CMDLINE_PREFIX}")?;
+        }
+        write!(f, "{}", self.arg())
+    }
+}
+
+/// A change to make to the boot files
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Edit<'a> {
+    Set(&'a Setting),
+    Unset(&'a Setting),
+}
+
+impl Edit<'_> {
+    fn setting(&self) -> &Setting {
+        match self {
+            Edit::Set(setting) | Edit::Unset(setting) => setting,
+        }
+    }
+}
+
+/// A line of config.txt
+#[derive(Debug)]
+enum Line {
+    /// `[filter]`, making the lines after it conditional until `[all]`
+    Filter(String),
+    Entry {
+        key: String,
+        value: String,
+    },
+    /// Comments, blank lines and anything else, kept as they were
+    Other(String),
+}
+
+/// config.txt, as edited by [Edit]s
+///
+/// Only unconditional settings are changed, those under a filter other than
+/// `[all]` are left for the pis they apply to
+#[derive(Debug)]
+struct ConfigTxt(Vec<Line>);
+
+impl ConfigTxt {
+    fn parse(contents: &str) -> Self {
+        Self(
+            contents
+                .lines()
+                .map(|line| {
+                    let trimmed = line.trim();
+                    if let Some(filter) = trimmed
+                        .strip_prefix('[')
+                        .and_then(|rest| rest.strip_suffix(']'))
+                    {
+                        Line::Filter(filter.to_owned())
+                    } else if !trimmed.starts_with('#')
+                        && let Some((key, value)) = trimmed.split_once('=')
+                    {
+                        Line::Entry {
+                            key: key.trim().to_owned(),
+                            value: value.trim().to_owned(),
+                        }
+                    } else {
+                        Line::Other(line.to_owned())
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Which lines apply to every pi, and whether the end of the file does
+    fn unconditional(&self) -> (Vec<bool>, bool) {
+        let mut all = true;
+        let lines = self
+            .0
+            .iter()
+            .map(|line| {
+                if let Line::Filter(filter) = line {
+                    all = filter == "all";
+                }
+                all
+            })
+            .collect();
+        (lines, all)
+    }
+
+    fn apply(&mut self, edit: Edit<'_>) {
+        let setting = edit.setting();
+        let (unconditional, at_end) = self.unconditional();
+        let mut replacement = match edit {
+            Edit::Set(setting) => Some(Line::Entry {
+                key: setting.key.clone(),
+                value: setting.value.clone().unwrap_or_default(),
+            }),
+            Edit::Unset(_) => None,
+        };
+        // The first match is replaced in place so the file keeps its order,
+        // and any others are dropped
+        let mut lines = Vec::new();
+        for (line, unconditional) in self.0.drain(..).zip(unconditional) {
+            let matches = unconditional
+                && matches!(&line, Line::Entry { key, value }
+                    if setting.matches(key, Some(value)));
+            if !matches {
+                lines.push(line);
+            } else if let Some(entry) = replacement.take() {
+                lines.push(entry);
+            }
+        }
+        if let Some(entry) = replacement {
+            if !at_end {
+                lines.push(Line::Filter(String::from("all")));
+            }
+            lines.push(entry);
+        }
+        self.0 = lines;
+    }
+}
+
+impl fmt::Display for ConfigTxt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.0 {
+            match line {
+                Line::Filter(filter) => writeln!(f, "[{filter}]")?,
+                Line::Entry { key, value } => writeln!(f, "{key}={value}")?,
+                Line::Other(line) => writeln!(f, "{line}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// cmdline.txt, a single line of kernel arguments
+#[derive(Debug)]
+struct Cmdline(Vec<String>);
+
+impl Cmdline {
+    fn parse(contents: &str) -> Self {
+        Self(contents.split_whitespace().map(String::from).collect())
+    }
+
+    fn apply(&mut self, edit: Edit<'_>) {
+        let setting = edit.setting();
+        let mut replacement = match edit {
+            Edit::Set(setting) => Some(setting.arg()),
+            Edit::Unset(_) => None,
+        };
+        let mut args = Vec::new();
+        for arg in self.0.drain(..) {
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !setting.matches(key, value) {
+                args.push(arg);
+            } else if let Some(replacement) = replacement.take() {
+                args.push(replacement);
+            }
+        }
+        args.extend(replacement);
+        self.0 = args;
+    }
+}
+
+impl fmt::Display for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.0.join(" "))
+    }
+}
+
+pub(crate) fn main(Args { name, set, unset }: Args) -> Result<()> {
+    let dir = firmware(&name)?;
+    if set.is_empty() && unset.is_empty() {
+        for file in [CONFIG_TXT, CMDLINE_TXT] {
+            println!("# {dir}/{file}");
+            print!("{}", cat(&name, format!("{dir}/{file}"))?);
+        }
+        return Ok(());
+    }
+
+    let edits = edits(&set, &unset)?;
+    let tempdir = tempfile::tempdir()?;
+    let pi_config = utils::pi_config(&name)?;
+    utils::ensure_pi_config(&name)?;
+    for file in [File::Config, File::Cmdline] {
+        let path = format!("{dir}/{}", file.name());
+        let before = cat(&name, &path)
+            .with_context(|| format!("Failed to read {path} on {name}"))?;
+        let Some(after) = edit(file, &before, &edits) else {
+            continue;
+        };
+        if after == before {
+            continue;
+        }
+
+        let local = tempdir.path().join(file.name());
+        fs::write(&local, &after)?;
+        push(&name, &[local], &pi_config)?;
+        Command::new("cp")
+            .arg(format!("{pi_config}/{}", file.name()))
+            .arg(&path)
+            .run_as_root()
+            .run_on_pi(&name)?
+            .check_status()?;
+        println!("Updated {path}");
+    }
+    println!("Reboot {name} for the changes to take effect");
+    Ok(())
+}
+
+/// Apply `edits` to the boot partition of an image
+pub(crate) fn apply(boot: &mut dyn Volume, edits: &[Edit<'_>]) -> Result<()> {
+    for file in [File::Config, File::Cmdline] {
+        // Not every image ships a config.txt, but every image needs a
+        // cmdline.txt to boot
+        let before = match (boot.read_to_string(file.name())?, file) {
+            (Some(contents), _) => contents,
+            (None, File::Config) => String::new(),
+            (None, File::Cmdline) => {
+                bail!("The boot partition has no {CMDLINE_TXT}")
+            }
+        };
+        if let Some(after) = edit(file, &before, edits) {
+            boot.write(file.name(), after.as_bytes(), Mode::FILE)?;
+        }
+    }
+    Ok(())
+}
+
+/// Settings to set followed by settings to unset, in the order they were
+/// given
+pub(crate) fn edits<'a>(
+    set: &'a [Setting],
+    unset: &'a [Setting],
+) -> Result<Vec<Edit<'a>>> {
+    if let Some(setting) = set
+        .iter()
+        .find(|setting| setting.file == File::Config && setting.value.is_none())
+    {
+        bail!("{CONFIG_TXT} settings need a value, as in {setting}=...")
+    }
+    // Otherwise every overlay or parameter would be removed
+    if let Some(setting) = unset.iter().find(|setting| {
+        setting.file == File::Config
+            && matches!(setting.key.as_str(), "dtoverlay" | "dtparam")
+            && setting.value.is_none()
+    }) {
+        bail!("Say which {setting} to unset, as in {setting}=<name>")
+    }
+    Ok(set
+        .iter()
+        .map(Edit::Set)
+        .chain(unset.iter().map(Edit::Unset))
+        .collect())
+}
+
+/// `cmdline` with the `edits` for cmdline.txt applied, for kernels that are
+/// booted without the firmware reading it
+pub(crate) fn edit_cmdline(cmdline: &str, edits: &[Edit<'_>]) -> String {
+    edit(File::Cmdline, cmdline, edits)
+        .unwrap_or_else(|| cmdline.to_owned())
+        .trim()
+        .to_owned()
+}
+
+/// `contents` of `file` with the `edits` for it applied, or None if none are
+/// for it
+fn edit(file: File, contents: &str, edits: &[Edit<'_>]) -> Option<String> {
+    let edits = edits
+        .iter()
+        .filter(|edit| edit.setting().file == file)
+        .collect::<Vec<_>>();
+    if edits.is_empty() {
+        return None;
+    }
+    Some(match file {
+        File::Config => {
+            let mut config = ConfigTxt::parse(contents);
+            for &&edit in &edits {
+                config.apply(edit);
+            }
+            config.to_string()
+        }
+        File::Cmdline => {
+            let mut cmdline = Cmdline::parse(contents);
+            for &&edit in &edits {
+                cmdline.apply(edit);
+            }
+            cmdline.to_string()
+        }
+    })
+}
+
+/// The directory the pi's boot partition is mounted on
+fn firmware(name: &str) -> Result<&'static str> {
+    // find succeeds whether or not it finds anything, so failing means the pi
+    // couldn't be asked rather than that it has no /boot/firmware
+    let found = Command::new("find")
+        .arg(LEGACY_FIRMWARE)
+        .args(["-maxdepth", "1", "-type", "d", "-name", "firmware"])
+        .run_on_pi(name)?
+        .check_output()
+        .with_context(|| format!("Failed to look for {FIRMWARE} on {name}"))?;
+    Ok(if found.trim() == FIRMWARE {
+        FIRMWARE
+    } else {
+        LEGACY_FIRMWARE
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# For more options see config.txt(5)
+dtparam=audio=on
+dtoverlay=vc4-kms-v3d
+dtoverlay=disable-bt
+
+[pi4]
+arm_boost=1
+dtoverlay=vc4-kms-v3d-pi4
+
+[all]
+  gpu_mem = 64
+";
+
+    fn config(set: &[&str], unset: &[&str]) -> Result<String> {
+        let set = set.iter().map(|s| s.parse()).collect::<Result<Vec<_>>>()?;
+        let unset = unset
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(edit(File::Config, CONFIG, &edits(&set, &unset)?).unwrap())
+    }
+
+    #[test]
+    fn parse_filters() {
+        let config = ConfigTxt::parse(CONFIG);
+        let (unconditional, at_end) = config.unconditional();
+        assert!(at_end);
+        let filtered = config
+            .0
+            .iter()
+            .zip(unconditional)
+            .filter_map(|(line, unconditional)| match line {
+                Line::Entry { key, .. } if !unconditional => Some(key.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(filtered, ["arm_boost", "dtoverlay"]);
+        assert!(matches!(
+            &config.0[10],
+            Line::Entry { key, value } if key == "gpu_mem" && value == "64"
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = ConfigTxt::parse(CONFIG).to_string();
+        assert_eq!(config, CONFIG.replace("  gpu_mem = 64", "gpu_mem=64"));
+        assert_eq!(ConfigTxt::parse(&config).to_string(), config);
+    }
+
+    #[test]
+    fn set_replaces_unconditional_settings() {
+        let config =
+            config(&["dtoverlay=vc4-kms-v3d,cma-128", "gpu_mem=128"], &[])
+                .unwrap();
+        assert!(config.contains("\ndtoverlay=vc4-kms-v3d,cma-128\n"));
+        assert!(config.contains("\ndtoverlay=disable-bt\n"));
+        assert!(
+            config
+                .contains("\n[pi4]\narm_boost=1\ndtoverlay=vc4-kms-v3d-pi4\n")
+        );
+        assert!(config.ends_with("[all]\ngpu_mem=128\n"));
+    }
+
+    #[test]
+    fn set_after_a_filter_goes_under_all() {
+        let contents = "[pi4]\narm_boost=1\n";
+        let setting = "arm_freq=1800".parse().unwrap();
+        let config =
+            edit(File::Config, contents, &[Edit::Set(&setting)]).unwrap();
+        assert_eq!(config, "[pi4]\narm_boost=1\n[all]\narm_freq=1800\n");
+    }
+
+    #[test]
+    fn set_is_idempotent() {
+        let set =
+            ["dtparam=i2c_arm=on", "arm_boost=0", "dtoverlay=vc4-kms-v3d"]
+                .map(|s| s.parse::<Setting>().unwrap());
+        let edits = edits(&set, &[]).unwrap();
+        let once = edit(File::Config, CONFIG, &edits).unwrap();
+        let twice = edit(File::Config, &once, &edits).unwrap();
+        assert_eq!(once, twice);
+        assert!(
+            once.ends_with(
+                "[all]\ngpu_mem=64\ndtparam=i2c_arm=on\narm_boost=0\n"
+            )
+        );
+    }
+
+    #[test]
+    fn unset_overlays_by_name() {
+        let config =
+            config(&[], &["dtoverlay=disable-bt", "dtparam=audio"]).unwrap();
+        assert!(!config.contains("disable-bt"));
+        assert!(!config.contains("audio"));
+        assert!(config.contains("\ndtoverlay=vc4-kms-v3d\n"));
+    }
+
+    #[test]
+    fn unset_overlays_needs_a_name() {
+        assert!(config(&[], &["dtoverlay"]).is_err());
+        assert!(config(&[], &["dtparam"]).is_err());
+        assert!(!config(&[], &["gpu_mem"]).unwrap().contains("gpu_mem"));
+    }
+
+    #[test]
+    fn cmdline_values_are_one_argument() {
+        assert!("cmdline:quiet splash".parse::<Setting>().is_err());
+        assert!("cmdline:console=tty1 quiet".parse::<Setting>().is_err());
+        assert!(Setting::cmdline("nfsroot", Some("/srv/my pi")).is_err());
+        assert!(Setting::cmdline("quiet", None).is_ok());
+        assert!("gpu_mem=64\narm_boost=1".parse::<Setting>().is_err());
+        assert!("dtoverlay=vc4-kms-v3d, cma-128".parse::<Setting>().is_ok());
+    }
+
+    #[test]
+    fn cmdline() {
+        let set = ["cmdline:root=/dev/nfs", "cmdline:ip=dhcp"]
+            .map(|s| s.parse::<Setting>().unwrap());
+        let unset = ["cmdline:init"].map(|s| s.parse::<Setting>().unwrap());
+        let edits = edits(&set, &unset).unwrap();
+        let cmdline = "console=tty1 root=PARTUUID=1234-02 rootwait \
+                       init=/usr/lib/raspberrypi-sys-mods/firstboot";
+        assert_eq!(
+            edit_cmdline(cmdline, &edits),
+            "console=tty1 root=/dev/nfs rootwait ip=dhcp"
+        );
+        assert!(edit(File::Config, CONFIG, &edits).is_none());
+    }
+}
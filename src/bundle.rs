@@ -0,0 +1,126 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, bail};
+use argh::FromArgs;
+
+use crate::payload::{Footer, Manifest, Payload};
+
+/// Embed a Raspbian image in a pi executable to produce a self-contained
+/// release binary
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "bundle")]
+pub(crate) struct Args {
+    /// the executable to embed the image in (or the bundle to check with
+    /// --verify)
+    #[argh(positional)]
+    executable: PathBuf,
+    /// the image to embed
+    #[argh(positional)]
+    image: Option<PathBuf>,
+    /// where to write the bundled executable
+    #[argh(option, short = 'o')]
+    output: Option<PathBuf>,
+    /// the OS name to record in the manifest, guessed from the image file
+    /// name if not given
+    #[argh(option)]
+    os: Option<String>,
+    /// the OS release date to record in the manifest, guessed from the image
+    /// file name if not given
+    #[argh(option)]
+    release: Option<String>,
+    /// the architecture to record in the manifest, guessed from the image
+    /// file name if not given
+    #[argh(option)]
+    arch: Option<String>,
+    /// check the footer and checksum of an existing bundle instead of
+    /// creating one
+    #[argh(switch)]
+    verify: bool,
+}
+
+pub(crate) fn main(
+    Args {
+        executable,
+        image,
+        output,
+        os,
+        release,
+        arch,
+        verify,
+    }: Args,
+) -> Result<()> {
+    if verify {
+        if image.is_some() || output.is_some() {
+            bail!("--verify only takes the bundle to check")
+        }
+        let payload = Payload::embedded(&executable)?;
+        prompt!("Verifying {}...", executable.display());
+        payload.verify()?;
+        println!("Done");
+        if let Some(manifest) = payload.manifest {
+            println!("{manifest}");
+        }
+        return Ok(());
+    }
+
+    let (Some(image), Some(output)) = (image, output) else {
+        bail!("Bundling requires an image and an --output path")
+    };
+    let manifest = Manifest::describe(&image, os, release, arch)?;
+    bundle(&executable, &image, &manifest, &output)?;
+    println!("Wrote {}", output.display());
+    println!("{manifest}");
+    Ok(())
+}
+
+/// Write `executable` with `image` and its manifest appended to `output`
+///
+/// If `executable` is already a bundle its embedded image is replaced
+fn bundle(
+    executable: &Path,
+    image: &Path,
+    manifest: &Manifest,
+    output: &Path,
+) -> Result<()> {
+    let mut exe = File::open(executable)
+        .with_context(|| format!("Failed to open {}", executable.display()))?;
+    let exe_len = match Footer::probe(&mut exe)? {
+        Some(footer) => footer.offset,
+        None => exe.metadata()?.len(),
+    };
+    let _ = exe.seek(SeekFrom::Start(0))?;
+
+    let mut image = File::open(image)
+        .with_context(|| format!("Failed to open {}", image.display()))?;
+
+    let dir = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut out = tempfile::NamedTempFile::new_in(dir)?;
+    let copied = io::copy(&mut exe.take(exe_len), out.as_file_mut())?;
+    if copied != exe_len {
+        bail!("{} changed while bundling", executable.display())
+    }
+    let copied = io::copy(&mut image, out.as_file_mut())?;
+    if copied != manifest.length {
+        bail!("Image changed while bundling")
+    }
+    let serialized = manifest.serialize();
+    out.as_file_mut().write_all(serialized.as_bytes())?;
+    Footer {
+        offset: exe_len,
+        length: manifest.length,
+        manifest_length: u64::try_from(serialized.len())?,
+    }
+    .write(out.as_file_mut())?;
+    out.as_file().sync_all()?;
+
+    fs::set_permissions(out.path(), fs::metadata(executable)?.permissions())?;
+    let _ = out.persist(output)?;
+    Ok(())
+}
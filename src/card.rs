@@ -0,0 +1,520 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read as _, Seek as _, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use argh::FromArgs;
+use command_ext::CommandExt as _;
+use flate2::write::GzEncoder;
+use sha2::{Digest as _, Sha256};
+use xz2::write::XzEncoder;
+
+use crate::{
+    device::Device,
+    flash,
+    image::{self, ROOT_PARTITION},
+    mbr,
+    os_release::OsRelease,
+    payload::{Compression, Manifest, Payload},
+    utils,
+    volume::Ext,
+};
+
+const MANIFEST_EXT: &str = "manifest";
+const XZ_LEVEL: u32 = 6;
+
+/// Back up SDCards to images and restore them
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "card")]
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Backup(BackupArgs),
+    Restore(RestoreArgs),
+}
+
+/// Copy an SDCard into an image, with a manifest recording its checksum
+/// alongside it
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "backup")]
+struct BackupArgs {
+    /// the image to write, compressed if it ends in .xz or .gz
+    #[argh(positional)]
+    file: PathBuf,
+    /// the device to read (e.g. /dev/sdb or mmcblk0), chosen from the
+    /// removable devices if not given
+    #[argh(option)]
+    device: Option<String>,
+    /// shrink the root filesystem to the space it uses first, so the image
+    /// doesn't hold the free space (it's grown back afterwards)
+    #[argh(switch)]
+    shrink: bool,
+    /// overwrite the image without asking
+    #[argh(switch, short = 'y')]
+    yes: bool,
+}
+
+/// Write an image made by pi card backup back onto an SDCard
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "restore")]
+struct RestoreArgs {
+    /// the image to write
+    #[argh(positional)]
+    file: PathBuf,
+    /// the device to write to (e.g. /dev/sdb or mmcblk0), chosen from the
+    /// removable devices if not given
+    #[argh(option)]
+    device: Option<String>,
+    /// read the SDCard back after writing it and check it matches the image
+    #[argh(switch)]
+    verify: bool,
+    /// grow the root partition and filesystem to fill the SDCard, for
+    /// images that were shrunk
+    #[argh(switch)]
+    grow: bool,
+    /// overwrite the SDCard without asking
+    #[argh(switch, short = 'y')]
+    yes: bool,
+}
+
+pub(crate) fn main(Args { command }: Args) -> Result<()> {
+    image::ensure_root()?;
+    match command {
+        Command::Backup(args) => backup(args),
+        Command::Restore(args) => restore(args),
+    }
+}
+
+fn backup(
+    BackupArgs {
+        file,
+        device,
+        shrink,
+        yes,
+    }: BackupArgs,
+) -> Result<()> {
+    let compression = compression(&file)?;
+    if file.exists() && !yes {
+        image::confirm_overwrite(file.display())?;
+    }
+    let device = Device::select(device.as_deref())?;
+    device.ensure_unused()?;
+
+    // Partitions past the last one are left out, cards are often bigger
+    // than what's on them
+    let partitions = mbr::read(&device.path)?;
+    let mut sector = mbr::sector(&device.path)?;
+    let last = last_partition(&partitions)
+        .ok_or_else(|| anyhow!("{device} has no partitions"))?;
+    let os = Ext::open(&device.path, *last)
+        .and_then(|mut root| OsRelease::read(&mut root))
+        .map_or_else(|_| String::from("unknown"), |os| os.to_string());
+
+    let shrunk = if shrink {
+        if last.kind != mbr::LINUX {
+            bail!(
+                "The last partition on {device} isn't ext4, it can't be shrunk"
+            )
+        }
+        Some(device.partition(last.number))
+    } else {
+        None
+    };
+    // Put the card back the way it was whether or not the backup works
+    let _grow = shrunk.as_deref().map(|partition| {
+        defer::defer(move || {
+            if let Err(e) = grow_back(partition) {
+                eprintln!("Failed to grow {} back: {e:#}", partition.display());
+            }
+        })
+    });
+    let length = match &shrunk {
+        Some(partition) => {
+            prompt!("Shrinking {}...", partition.display());
+            fsck(partition)?;
+            resize(partition, true)?;
+            let size = filesystem_size(partition)?;
+            println!("Done, {} used", utils::format_size(size));
+            shrink_table(&mut sector, last, size)?
+        }
+        None => last.start + last.size,
+    };
+
+    let mut card = File::open(&device.path)?;
+    let _ = card.seek(SeekFrom::Start(sector.len() as u64))?;
+    let mut src =
+        Cursor::new(sector).chain(card.take(length - sector.len() as u64));
+    let out = Hashed::new(
+        File::create(&file)
+            .with_context(|| format!("Failed to create {}", file.display()))?,
+    );
+    println!(
+        "Backing up {} of {device} (this may take a while)...",
+        utils::format_size(length)
+    );
+    let out = match compression {
+        Compression::Xz => {
+            let mut out = XzEncoder::new(out, XZ_LEVEL);
+            flash::read(&mut src, length, &mut out)?;
+            out.finish()?
+        }
+        Compression::Gzip => {
+            let mut out = GzEncoder::new(out, flate2::Compression::default());
+            flash::read(&mut src, length, &mut out)?;
+            out.finish()?
+        }
+        _ => {
+            let mut out = out;
+            flash::read(&mut src, length, &mut out)?;
+            out
+        }
+    };
+    out.file.sync_all()?;
+
+    let manifest = out.manifest(os, SystemTime::now());
+    fs::write(manifest_path(&file), manifest.serialize())?;
+    println!("{manifest}");
+    Ok(())
+}
+
+fn restore(
+    RestoreArgs {
+        file,
+        device,
+        verify,
+        grow,
+        yes,
+    }: RestoreArgs,
+) -> Result<()> {
+    let manifest = manifest_path(&file);
+    let image = if manifest.is_file() {
+        let manifest = Manifest::parse(&fs::read_to_string(&manifest)?)
+            .with_context(|| {
+                format!("Failed to parse {}", manifest.display())
+            })?;
+        Payload::from_file_with_manifest(&file, manifest)?
+    } else {
+        Payload::from_file(&file)?
+    };
+    let device = Device::select(device.as_deref())?;
+    device.ensure_unused()?;
+    if !yes {
+        image::confirm_overwrite(format!("the SDCard in {device}"))?;
+    }
+
+    image::verify_checksum(&image)?;
+    println!(
+        "Restoring {} (this may take a while)...",
+        image::describe(&image)?
+    );
+    let mut card = OpenOptions::new().write(true).open(&device.path)?;
+    let written = flash::write(&image, &mut card, true)?;
+    if verify {
+        println!("Verifying {}...", device.path.display());
+        flash::verify(&image, &device.path, &written, true)?;
+    }
+    device.reread_partitions()?;
+
+    if grow {
+        grow_root(&device, &mut card)?;
+    }
+    Ok(())
+}
+
+/// Undo shrinking the filesystem for a backup
+fn grow_back(partition: &Path) -> Result<()> {
+    prompt!("Growing {} back...", partition.display());
+    resize(partition, false)?;
+    println!("Done");
+    Ok(())
+}
+
+/// Grow the root partition, and its filesystem, to the end of the card
+fn grow_root(device: &Device, card: &mut File) -> Result<()> {
+    let mut sector = mbr::sector(&device.path)?;
+    grow_table(&mut sector, device.size).with_context(|| {
+        format!("Can't grow the root partition on {device}")
+    })?;
+
+    let partition = device.partition(ROOT_PARTITION);
+    prompt!("Growing {}...", partition.display());
+    let _ = card.seek(SeekFrom::Start(0))?;
+    card.write_all(&sector)?;
+    card.sync_all()?;
+    device.reread_partitions()?;
+    fsck(&partition)?;
+    resize(&partition, false)?;
+    println!("Done");
+    Ok(())
+}
+
+/// The partition reaching furthest into the card, backups stop at its end
+fn last_partition(partitions: &[mbr::Partition]) -> Option<&mbr::Partition> {
+    partitions
+        .iter()
+        .max_by_key(|partition| partition.start + partition.size)
+}
+
+/// Cut `last` down to its shrunk filesystem of `size` bytes in `sector`,
+/// returning how much of the card the backup then covers
+fn shrink_table(
+    sector: &mut [u8; 512],
+    last: &mbr::Partition,
+    size: u64,
+) -> Result<u64> {
+    mbr::resize(sector, last.number, size)?;
+    Ok(last.start + size)
+}
+
+/// Stretch the root partition in `sector` to the end of a card of
+/// `card_size` bytes, if nothing comes after it
+fn grow_table(sector: &mut [u8; 512], card_size: u64) -> Result<()> {
+    let partitions = mbr::parse(sector)?;
+    let root = partitions
+        .iter()
+        .find(|partition| partition.number == ROOT_PARTITION)
+        .ok_or_else(|| anyhow!("There's no partition {ROOT_PARTITION}"))?;
+    let last = partitions
+        .iter()
+        .all(|partition| partition.start <= root.start);
+    if root.kind != mbr::LINUX || !last {
+        bail!("The root partition isn't the last one or isn't ext4")
+    }
+    let size = card_size.checked_sub(root.start).ok_or_else(|| {
+        anyhow!("The root partition starts past the end of the card")
+    })?;
+    mbr::resize(sector, ROOT_PARTITION, size)
+}
+
+/// How the image is compressed, from its extension
+fn compression(file: &Path) -> Result<Compression> {
+    match file.extension().and_then(|ext| ext.to_str()) {
+        Some("xz") => Ok(Compression::Xz),
+        Some("gz") => Ok(Compression::Gzip),
+        Some("zip") => bail!("Images can't be written as zip archives"),
+        _ => Ok(Compression::None),
+    }
+}
+
+/// Where the manifest for a backup goes, next to it
+fn manifest_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(format!(".{MANIFEST_EXT}"));
+    PathBuf::from(path)
+}
+
+/// Check and repair an ext4 filesystem, which resize2fs insists on
+fn fsck(partition: &Path) -> Result<()> {
+    let output = process::Command::new("e2fsck")
+        .arg("-fp")
+        .arg(partition)
+        .output()?;
+    // 1 means problems were found and fixed
+    if !matches!(output.status.code(), Some(0 | 1)) {
+        bail!(
+            "e2fsck found problems on {} it couldn't fix: {}",
+            partition.display(),
+            String::from_utf8_lossy(&output.stdout).trim()
+        )
+    }
+    Ok(())
+}
+
+/// Shrink an ext4 filesystem as far as it goes, or grow it to fill its
+/// partition
+fn resize(partition: &Path, shrink: bool) -> Result<()> {
+    let mut command = process::Command::new("resize2fs");
+    if shrink {
+        let _ = command.arg("-M");
+    }
+    let _ = command.arg(partition).check_output()?;
+    Ok(())
+}
+
+/// The size of the ext4 filesystem on `partition` in bytes
+fn filesystem_size(partition: &Path) -> Result<u64> {
+    let header = process::Command::new("dumpe2fs")
+        .arg("-h")
+        .arg(partition)
+        .check_output()?;
+    parse_filesystem_size(&header).ok_or_else(|| {
+        anyhow!("dumpe2fs didn't report the size of {}", partition.display())
+    })
+}
+
+/// The block count times the block size from `dumpe2fs -h`'s output
+fn parse_filesystem_size(header: &str) -> Option<u64> {
+    let field = |name: &str| {
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    field("Block count:")?.checked_mul(field("Block size:")?)
+}
+
+/// Counts and hashes what's written to the file, for the manifest
+struct Hashed {
+    file: File,
+    hasher: Sha256,
+    length: u64,
+}
+
+impl Hashed {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            hasher: Sha256::new(),
+            length: 0,
+        }
+    }
+}
+
+impl Hashed {
+    /// The manifest for what's been written, a backup of an `os` card
+    /// taken at `now`
+    fn manifest(self, os: String, now: SystemTime) -> Manifest {
+        Manifest {
+            os,
+            release: humantime::format_rfc3339_seconds(now).to_string()[..10]
+                .to_owned(),
+            arch: String::from("unknown"),
+            length: self.length,
+            sha256: utils::hex(&self.hasher.finalize()),
+        }
+    }
+}
+
+impl Write for Hashed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const SECTOR: u64 = 512;
+
+    #[test]
+    fn backups_end_with_the_last_partition() {
+        let partitions = mbr::parse(&mbr::table(&[
+            (mbr::LINUX, 9216, 4096),
+            (mbr::FAT32_LBA, 8192, 1024),
+        ]))
+        .unwrap();
+        assert_eq!(last_partition(&partitions).unwrap().number, 1);
+        assert!(last_partition(&[]).is_none());
+    }
+
+    #[test]
+    fn shrinking_cuts_the_backup_short() {
+        let mut sector = mbr::table(&[
+            (mbr::FAT32_LBA, 8192, 1024),
+            (mbr::LINUX, 9216, 4096),
+        ]);
+        let last = mbr::parse(&sector).unwrap()[1];
+        let length = shrink_table(&mut sector, &last, 1000 * SECTOR).unwrap();
+        assert_eq!(length, (9216 + 1000) * SECTOR);
+        let root = mbr::parse(&sector).unwrap()[1];
+        assert_eq!(root.start + root.size, length);
+    }
+
+    #[test]
+    fn growing_fills_the_card() {
+        let mut sector = mbr::table(&[
+            (mbr::FAT32_LBA, 8192, 1024),
+            (mbr::LINUX, 9216, 1000),
+        ]);
+        grow_table(&mut sector, 1 << 30).unwrap();
+        let partitions = mbr::parse(&sector).unwrap();
+        assert_eq!(partitions[0].size, 1024 * SECTOR);
+        assert_eq!(partitions[1].start + partitions[1].size, 1 << 30);
+    }
+
+    #[test]
+    fn only_a_trailing_ext4_root_grows() {
+        for partitions in [
+            [(mbr::FAT32_LBA, 8192, 1024), (mbr::FAT32_LBA, 9216, 1000)],
+            [(mbr::LINUX, 9216, 1000), (mbr::LINUX, 8192, 1024)],
+        ] {
+            let mut sector = mbr::table(&partitions);
+            assert!(grow_table(&mut sector, 1 << 30).is_err());
+        }
+        let mut sector = mbr::table(&[(mbr::FAT32_LBA, 8192, 1024)]);
+        assert!(grow_table(&mut sector, 1 << 30).is_err());
+        // A card smaller than the image
+        let mut sector = mbr::table(&[
+            (mbr::FAT32_LBA, 8192, 1024),
+            (mbr::LINUX, 9216, 1000),
+        ]);
+        assert!(grow_table(&mut sector, 1 << 20).is_err());
+    }
+
+    #[test]
+    fn dumpe2fs_sizes() {
+        let header = "Filesystem volume name:   rootfs\n\
+                      Block count:              1000\n\
+                      Reserved block count:     50\n\
+                      Block size:               4096\n";
+        assert_eq!(parse_filesystem_size(header), Some(4_096_000));
+        assert_eq!(parse_filesystem_size("Block count: 1000\n"), None);
+    }
+
+    #[test]
+    fn manifest_describes_what_was_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.img");
+        let mut out = Hashed::new(File::create(&path).unwrap());
+        out.write_all(b"backed up card").unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_732_000_000);
+        let manifest = out.manifest(String::from("raspios bookworm"), now);
+        assert_eq!(manifest.os, "raspios bookworm");
+        assert_eq!(manifest.release, "2024-11-19");
+        assert_eq!(manifest.length, 14);
+        assert_eq!(
+            manifest.sha256,
+            utils::sha256(File::open(&path).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn manifest_next_to_the_backup() {
+        assert_eq!(
+            manifest_path(Path::new("/backups/pi.img.xz")),
+            Path::new("/backups/pi.img.xz.manifest")
+        );
+        assert_eq!(
+            compression(Path::new("pi.img.xz")).unwrap(),
+            Compression::Xz
+        );
+        assert_eq!(
+            compression(Path::new("pi.img.gz")).unwrap(),
+            Compression::Gzip
+        );
+        assert_eq!(
+            compression(Path::new("pi.img")).unwrap(),
+            Compression::None
+        );
+        assert!(compression(Path::new("pi.zip")).is_err());
+    }
+}
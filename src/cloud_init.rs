@@ -0,0 +1,306 @@
+use std::fmt::Write as _;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    first_boot,
+    host_keys::HostKey,
+    locale::Locale,
+    static_ip::{self, StaticIp},
+    wifi::{Key, Network},
+};
+
+/// cloud-init's NoCloud configuration, on the boot partition
+pub(crate) const USER_DATA: &str = "user-data";
+pub(crate) const NETWORK_CONFIG: &str = "network-config";
+/// Where Ubuntu mounts the boot partition
+const BOOT_DIR: &str = "/boot/firmware";
+// The groups Ubuntu gives its default user
+const GROUPS: &str = "adm, audio, cdrom, dialout, dip, plugdev, sudo, video";
+const WIFI_INTERFACE: &str = "wlan0";
+
+/// Everything user-data sets up
+#[derive(Debug)]
+pub(crate) struct UserData<'a> {
+    pub(crate) hostname: &'a str,
+    pub(crate) user: &'a str,
+    pub(crate) password_hash: &'a str,
+    pub(crate) authorized_key: &'a str,
+    pub(crate) host_keys: &'a [HostKey],
+    pub(crate) locale: &'a Locale,
+    pub(crate) packages: &'a [String],
+    /// Whether there's a first boot script on the boot partition to run
+    pub(crate) first_boot: bool,
+}
+
+impl UserData<'_> {
+    /// The `#cloud-config` file
+    pub(crate) fn render(&self) -> String {
+        let mut data = String::from("#cloud-config\n");
+        let _ = writeln!(data, "hostname: {}", quote(self.hostname));
+        let _ = writeln!(data, "manage_etc_hosts: true");
+
+        // Replaces the image's default user, like userconf does
+        let _ = writeln!(data, "users:");
+        let _ = writeln!(data, "  - name: {}", quote(self.user));
+        let _ = writeln!(data, "    groups: [{GROUPS}]");
+        let _ = writeln!(data, "    shell: /bin/bash");
+        let _ = writeln!(data, "    sudo: \"ALL=(ALL) NOPASSWD:ALL\"");
+        let _ = writeln!(data, "    lock_passwd: false");
+        let _ = writeln!(data, "    passwd: {}", quote(self.password_hash));
+        let _ = writeln!(data, "    ssh_authorized_keys:");
+        let _ = writeln!(data, "      - {}", quote(self.authorized_key));
+        let _ = writeln!(data, "chpasswd:");
+        let _ = writeln!(data, "  expire: false");
+        // Password logins are only for getting in without the key
+        let _ =
+            writeln!(data, "ssh_pwauth: {}", self.authorized_key.is_empty());
+
+        let _ = writeln!(data, "ssh_deletekeys: true");
+        let _ = writeln!(data, "ssh_keys:");
+        for key in self.host_keys {
+            let _ = writeln!(
+                data,
+                "  {}_private: {}",
+                key.kind,
+                quote(&String::from_utf8_lossy(&key.private))
+            );
+            let _ = writeln!(
+                data,
+                "  {}_public: {}",
+                key.kind,
+                quote(key.public.trim())
+            );
+        }
+
+        if let Some(timezone) = &self.locale.timezone {
+            let _ = writeln!(data, "timezone: {}", quote(timezone));
+        }
+        if let Some(lang) = &self.locale.lang {
+            let _ = writeln!(data, "locale: {}", quote(lang));
+        }
+        if let Some(keyboard) = &self.locale.keyboard {
+            let _ = writeln!(data, "keyboard:");
+            let _ = writeln!(data, "  layout: {}", quote(keyboard));
+        }
+
+        if !self.packages.is_empty() {
+            let _ = writeln!(data, "package_update: true");
+            let _ = writeln!(data, "packages:");
+            for package in self.packages {
+                let _ = writeln!(data, "  - {}", quote(package));
+            }
+        }
+
+        // runcmd runs after packages are installed, once per instance
+        if self.first_boot {
+            let _ = writeln!(data, "runcmd:");
+            let _ = writeln!(
+                data,
+                "  - [\"sh\", \"-c\", {}]",
+                quote(&first_boot::command(BOOT_DIR))
+            );
+        }
+        data
+    }
+}
+
+/// A netplan network-config joining `networks`, with `static_ip` on its
+/// interface and DHCP everywhere else
+pub(crate) fn network_config(
+    country: &str,
+    networks: &[Network],
+    static_ip: Option<&StaticIp>,
+) -> Result<String> {
+    let mut conf = String::from("version: 2\n");
+    let wired = static_ip.filter(|ip| !ip.is_wifi());
+    let _ = writeln!(conf, "ethernets:");
+    let mut interfaces = vec![static_ip::DEFAULT_INTERFACE];
+    if let Some(ip) = wired
+        && ip.interface != static_ip::DEFAULT_INTERFACE
+    {
+        interfaces.push(&ip.interface);
+    }
+    for interface in interfaces {
+        let ip = wired.filter(|ip| ip.interface == interface);
+        let _ = writeln!(conf, "  {interface}:");
+        conf.push_str(&StaticIp::netplan(ip, "    "));
+        // Don't hold up boot waiting for a cable
+        let _ = writeln!(conf, "    optional: true");
+    }
+
+    if networks.is_empty() {
+        return Ok(conf);
+    }
+    let wireless = static_ip.filter(|ip| ip.is_wifi());
+    let _ = writeln!(conf, "wifis:");
+    let _ = writeln!(
+        conf,
+        "  {}:",
+        wireless.map_or(WIFI_INTERFACE, |ip| &ip.interface)
+    );
+    conf.push_str(&StaticIp::netplan(wireless, "    "));
+    let _ = writeln!(conf, "    optional: true");
+    let _ = writeln!(conf, "    regulatory-domain: {}", quote(country));
+    // netplan has no priorities, the networks are left for wpa_supplicant
+    // to choose between
+    let _ = writeln!(conf, "    access-points:");
+    for network in networks {
+        let ssid = network.ssid.as_str().ok_or_else(|| {
+            anyhow!(
+                "{} can't be given to cloud-init, it isn't UTF-8",
+                network.ssid
+            )
+        })?;
+        let _ = writeln!(conf, "      {}:", quote(ssid));
+        if network.hidden {
+            let _ = writeln!(conf, "        hidden: true");
+        }
+        let _ = writeln!(conf, "        auth:");
+        match &network.key {
+            Key::Open => {
+                let _ = writeln!(conf, "          key-management: none");
+            }
+            Key::Wpa2(psk) => {
+                let _ = writeln!(conf, "          key-management: psk");
+                let _ = writeln!(conf, "          password: \"{psk}\"");
+            }
+            Key::Sae(password) => {
+                let _ = writeln!(conf, "          key-management: sae");
+                let _ =
+                    writeln!(conf, "          password: {}", quote(password));
+            }
+        }
+    }
+    Ok(conf)
+}
+
+/// A double quoted YAML scalar, escaped the way JSON escapes strings
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wifi::{Security, Ssid};
+
+    fn locale() -> Locale {
+        Locale {
+            timezone: Some("Europe/London".to_owned()),
+            lang: None,
+            keyboard: None,
+        }
+    }
+
+    #[test]
+    fn user_data() {
+        let locale = locale();
+        let host_keys = [HostKey {
+            kind: "ed25519",
+            private: b"PRIVATE\nKEY\n".to_vec(),
+            public: "ssh-ed25519 AAAA root@pi\n".to_owned(),
+            fingerprint: String::new(),
+        }];
+        let data = UserData {
+            hostname: "pi",
+            user: "admin",
+            password_hash: "$6$salt$hash",
+            authorized_key: "ssh-ed25519 BBBB me",
+            host_keys: &host_keys,
+            locale: &locale,
+            packages: &["vim".to_owned()],
+            first_boot: false,
+        }
+        .render();
+        assert!(data.starts_with("#cloud-config\nhostname: \"pi\"\n"));
+        assert!(data.contains("  - name: \"admin\"\n"));
+        assert!(data.contains("    passwd: \"$6$salt$hash\"\n"));
+        assert!(data.contains("      - \"ssh-ed25519 BBBB me\"\n"));
+        assert!(data.contains("ssh_pwauth: false\n"));
+        assert!(data.contains("  ed25519_private: \"PRIVATE\\nKEY\\n\"\n"));
+        assert!(
+            data.contains("  ed25519_public: \"ssh-ed25519 AAAA root@pi\"\n")
+        );
+        assert!(data.contains("timezone: \"Europe/London\"\n"));
+        assert!(!data.contains("locale:"));
+        assert!(data.contains("packages:\n  - \"vim\"\n"));
+        assert!(!data.contains("runcmd:"));
+    }
+
+    #[test]
+    fn password_logins_without_a_key() {
+        let locale = locale();
+        let data = UserData {
+            hostname: "pi",
+            user: "admin",
+            password_hash: "$6$salt$hash",
+            authorized_key: "",
+            host_keys: &[],
+            locale: &locale,
+            packages: &[],
+            first_boot: true,
+        }
+        .render();
+        assert!(data.contains("ssh_pwauth: true\n"));
+        assert!(!data.contains("packages:"));
+        assert!(data.contains("runcmd:\n  - [\"sh\", \"-c\", "));
+    }
+
+    #[test]
+    fn wired_only_network_config() {
+        assert_eq!(
+            network_config("GB", &[], None).unwrap(),
+            "version: 2\nethernets:\n  eth0:\n    dhcp4: true\n    \
+             optional: true\n"
+        );
+    }
+
+    #[test]
+    fn wifi_network_config() {
+        let ssid = Ssid::parse("home").unwrap();
+        let networks = [Network {
+            key: Key::new(Security::Sae, &ssid, Some("sesame!")).unwrap(),
+            ssid,
+            priority: 0,
+            hidden: true,
+        }];
+        let static_ip = StaticIp::new(
+            "wlan0".to_owned(),
+            &["192.168.1.2/24".parse().unwrap()],
+            &[],
+            Vec::new(),
+        )
+        .unwrap();
+        let conf = network_config("GB", &networks, static_ip.as_ref()).unwrap();
+        assert!(conf.contains("  eth0:\n    dhcp4: true\n"));
+        assert!(conf.contains(
+            "wifis:\n  wlan0:\n    dhcp4: false\n    addresses:\n      \
+             - \"192.168.1.2/24\"\n"
+        ));
+        assert!(conf.contains("    regulatory-domain: \"GB\"\n"));
+        assert!(conf.contains(
+            "      \"home\":\n        hidden: true\n        auth:\n          \
+             key-management: sae\n          password: \"sesame!\"\n"
+        ));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
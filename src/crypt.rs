@@ -0,0 +1,174 @@
+use std::{fs::File, io::Read as _};
+
+use anyhow::{Result, bail};
+use sha2::{Digest as _, Sha512};
+
+const ROUNDS: usize = 5000;
+const SALT_LEN: usize = 16;
+const ALPHABET: &[u8; 64] =
+    b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// Order the digest bytes are encoded in, three at a time
+const PERMUTATION: [[usize; 3]; 21] = [
+    [0, 21, 42],
+    [22, 43, 1],
+    [44, 2, 23],
+    [3, 24, 45],
+    [25, 46, 4],
+    [47, 5, 26],
+    [6, 27, 48],
+    [28, 49, 7],
+    [50, 8, 29],
+    [9, 30, 51],
+    [31, 52, 10],
+    [53, 11, 32],
+    [12, 33, 54],
+    [34, 55, 13],
+    [56, 14, 35],
+    [15, 36, 57],
+    [37, 58, 16],
+    [59, 17, 38],
+    [18, 39, 60],
+    [40, 61, 19],
+    [62, 20, 41],
+];
+
+/// Hash `password` for /etc/shadow with a random salt, the same as
+/// `openssl passwd -6`
+pub(crate) fn sha512(password: &str) -> Result<String> {
+    let mut random = [0; SALT_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let salt = random
+        .iter()
+        .map(|byte| char::from(ALPHABET[usize::from(byte % 64)]))
+        .collect::<String>();
+    Ok(sha512_with_salt(password, &salt))
+}
+
+/// Check `hash` looks like something [sha512] produced
+pub(crate) fn validate(hash: &str) -> Result<()> {
+    let mut parts = hash.split('$');
+    let valid = parts.next() == Some("")
+        && parts.next() == Some("6")
+        && parts.next().is_some_and(|salt| !salt.is_empty())
+        && parts.next().is_some_and(|hash| {
+            hash.len() == 86
+                && hash.bytes().all(|byte| ALPHABET.contains(&byte))
+        })
+        && parts.next().is_none();
+    if !valid {
+        bail!("Not a SHA-512 crypt hash (expected $6$<salt>$<hash>)")
+    }
+    Ok(())
+}
+
+/// SHA-512 crypt as specified at https://www.akkadia.org/drepper/SHA-crypt.txt
+fn sha512_with_salt(password: &str, salt: &str) -> String {
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(SALT_LEN)];
+
+    let alternate = Sha512::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut digest = Sha512::new().chain_update(password).chain_update(salt);
+    digest.update(repeat(&alternate, password.len()));
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            digest.update(alternate);
+        } else {
+            digest.update(password);
+        }
+        length >>= 1;
+    }
+    let mut digest = digest.finalize();
+
+    let mut p = Sha512::new();
+    for _ in 0..password.len() {
+        p.update(password);
+    }
+    let p = repeat(&p.finalize(), password.len());
+
+    let mut s = Sha512::new();
+    for _ in 0..16 + usize::from(digest[0]) {
+        s.update(salt);
+    }
+    let s = repeat(&s.finalize(), salt.len());
+
+    for round in 0..ROUNDS {
+        let mut next = Sha512::new();
+        if round % 2 == 1 {
+            next.update(&p);
+        } else {
+            next.update(digest);
+        }
+        if round % 3 != 0 {
+            next.update(&s);
+        }
+        if round % 7 != 0 {
+            next.update(&p);
+        }
+        if round % 2 == 1 {
+            next.update(digest);
+        } else {
+            next.update(&p);
+        }
+        digest = next.finalize();
+    }
+
+    let mut encoded = String::new();
+    for [a, b, c] in PERMUTATION {
+        encode(&mut encoded, [digest[a], digest[b], digest[c]], 4);
+    }
+    encode(&mut encoded, [0, 0, digest[63]], 2);
+
+    format!("$6${}${encoded}", String::from_utf8_lossy(salt))
+}
+
+/// `bytes` repeated to fill `len` bytes
+fn repeat(bytes: &[u8], len: usize) -> Vec<u8> {
+    bytes.iter().copied().cycle().take(len).collect()
+}
+
+fn encode(out: &mut String, [high, mid, low]: [u8; 3], chars: usize) {
+    let mut word =
+        (u32::from(high) << 16) | (u32::from(mid) << 8) | u32::from(low);
+    for _ in 0..chars {
+        out.push(char::from(ALPHABET[(word & 0x3f) as usize]));
+        word >>= 6;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vectors from the specification without rounds=
+    #[test]
+    fn sha512_known_answers() {
+        assert_eq!(
+            sha512_with_salt("Hello world!", "saltstring"),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+        );
+        assert_eq!(
+            sha512_with_salt("This is just a test", "toolongsaltstring"),
+            "$6$toolongsaltstrin$lQ8jolhgVRVhY4b5pZKaysCLi0QBxGoNeKQzQ3glMhwllF7oGDZxUhx1yxdYcz/e1JSbq3y6JMxxl8audkUEm0"
+        );
+    }
+
+    #[test]
+    fn sha512_is_valid() {
+        let hash = sha512("password").unwrap();
+        validate(&hash).unwrap();
+        assert_ne!(hash, sha512("password").unwrap());
+    }
+
+    #[test]
+    fn validate_rejects_other_hashes() {
+        for hash in ["", "password", "$1$salt$hash", "$6$$", "$6$salt$short"] {
+            assert!(validate(hash).is_err(), "{hash}");
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use command_ext::CommandExt as _;
+use nix::sys::stat;
+
+use crate::utils;
+
+const SYS_BLOCK: &str = "/sys/block";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const SWAPS: &str = "/proc/swaps";
+// sysfs always reports sizes in 512 byte sectors, regardless of the logical
+// block size of the device
+const SECTOR_SIZE: u64 = 512;
+const PARTITION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A whole-disk block device (as opposed to a partition) known to sysfs
+#[derive(Debug, Clone)]
+pub(crate) struct Device {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) vendor: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) removable: bool,
+    /// An SD card in an MMC reader, as opposed to eMMC (whose boot and rpmb
+    /// hardware partitions show up as devices of their own)
+    sd_card: bool,
+}
+
+impl Device {
+    /// Look up a device by name (`sdb`) or path (`/dev/sdb`)
+    pub(crate) fn open(device: impl AsRef<str>) -> Result<Self> {
+        let device = device.as_ref();
+        let path = Path::new(device);
+        let path = if path.is_absolute() {
+            path.canonicalize().with_context(|| {
+                format!("{device} doesn't exist. Is the SDCard plugged in?")
+            })?
+        } else {
+            Path::new("/dev").join(device)
+        };
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid device path {device}"))?;
+        let sysfs = Path::new(SYS_BLOCK).join(name);
+        if !sysfs.exists() {
+            bail!(
+                "{device} is not a whole-disk block device (partitions can't \
+                 be imaged)"
+            )
+        }
+        Self::from_sysfs(&sysfs)
+    }
+
+    /// All removable devices with media present
+    ///
+    /// Built in MMC readers usually don't set the removable flag so SD cards
+    /// in them are included regardless
+    pub(crate) fn enumerate() -> Result<Vec<Self>> {
+        let mut devices = Vec::new();
+        for entry in fs::read_dir(SYS_BLOCK)? {
+            let device = Self::from_sysfs(&entry?.path())?;
+            if (device.removable || device.sd_card) && device.size > 0 {
+                devices.push(device);
+            }
+        }
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(devices)
+    }
+
+    /// Use the named device if given, otherwise ask the user to pick one of
+    /// the removable devices
+    pub(crate) fn select(device: Option<&str>) -> Result<Self> {
+        if let Some(device) = device {
+            return Self::open(device);
+        }
+
+        let mut devices = Self::enumerate()?;
+        match devices.len() {
+            0 => bail!("No removable devices found. Is the SDCard plugged in?"),
+            1 => return Ok(devices.remove(0)),
+            _ => (),
+        }
+        if !utils::interactive() {
+            bail!(
+                "Found {} removable devices, use --device to pick one",
+                devices.len()
+            )
+        }
+
+        println!("Available devices:");
+        for (index, device) in devices.iter().enumerate() {
+            println!("  [{}] {device}", index + 1);
+        }
+        prompt!("Select a device [1-{}]: ", devices.len());
+        let choice = utils::read_line()?;
+        let index = choice
+            .parse::<usize>()
+            .ok()
+            .filter(|index| (1..=devices.len()).contains(index))
+            .ok_or_else(|| anyhow!("Invalid selection: {choice}"))?;
+        Ok(devices.remove(index - 1))
+    }
+
+    /// The path of the nth (1-based) partition on the device
+    ///
+    /// Devices whose names end in a digit (`mmcblk0`, `nvme0n1`, `loop0`)
+    /// separate the partition number with a `p`
+    pub(crate) fn partition(&self, n: u32) -> PathBuf {
+        let separator = if self.name.ends_with(|c: char| c.is_ascii_digit()) {
+            "p"
+        } else {
+            ""
+        };
+        PathBuf::from(format!("{}{separator}{n}", self.path.display()))
+    }
+
+    /// Get the kernel to pick up a freshly written partition table and wait
+    /// for the partition device nodes to appear
+    pub(crate) fn reread_partitions(&self) -> Result<()> {
+        Command::new("blockdev")
+            .arg("--rereadpt")
+            .arg(&self.path)
+            .check_status()?;
+        let first = self.partition(1);
+        let deadline = Instant::now() + PARTITION_TIMEOUT;
+        while !first.exists() {
+            if Instant::now() > deadline {
+                bail!(
+                    "{} didn't appear after rereading the partition table",
+                    first.display()
+                )
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// Refuse to continue if the device (or anything stacked on top of it)
+    /// backs the running root, a mounted filesystem or active swap
+    pub(crate) fn ensure_unused(&self) -> Result<()> {
+        let sysfs = Path::new(SYS_BLOCK).join(&self.name);
+        let mut backing = Vec::new();
+        collect_backing(&sysfs, &mut backing)?;
+
+        let root = fs::metadata("/")?.dev();
+        let root = (stat::major(root), stat::minor(root));
+        if backing.iter().any(|block| block.dev == root) {
+            bail!(
+                "{} backs the running root filesystem, refusing to image it",
+                self.path.display()
+            )
+        }
+
+        for line in fs::read_to_string(MOUNTINFO)?.lines() {
+            let Some(mount) = Mount::parse(line) else {
+                continue;
+            };
+            if backing.iter().any(|block| {
+                Some(block.dev) == mount.dev
+                    || mount.source == Path::new("/dev").join(&block.name)
+            }) {
+                bail!(
+                    "{} backs the filesystem mounted at {}, unmount it first",
+                    self.path.display(),
+                    mount.target
+                )
+            }
+        }
+
+        for line in fs::read_to_string(SWAPS)?.lines().skip(1) {
+            let Some(source) = line.split_whitespace().next() else {
+                continue;
+            };
+            let source = canonical(source);
+            if backing
+                .iter()
+                .any(|block| source == Path::new("/dev").join(&block.name))
+            {
+                bail!(
+                    "{} backs active swap space ({}), swapoff it first",
+                    self.path.display(),
+                    source.display()
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    fn from_sysfs(sysfs: &Path) -> Result<Self> {
+        let name = sysfs
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid sysfs entry {}", sysfs.display()))?
+            .to_owned();
+        let size = read_attr(sysfs, "size")?
+            .map(|size| size.parse::<u64>())
+            .transpose()?
+            .unwrap_or_default()
+            * SECTOR_SIZE;
+        let removable = read_attr(sysfs, "removable")?.as_deref() == Some("1");
+        let sd_card = read_attr(sysfs, "device/type")?.as_deref() == Some("SD");
+        Ok(Self {
+            path: Path::new("/dev").join(&name),
+            name,
+            size,
+            vendor: read_attr(sysfs, "device/vendor")?,
+            model: read_attr(sysfs, "device/model")?
+                .or(read_attr(sysfs, "device/name")?),
+            removable,
+            sd_card,
+        })
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}",
+            self.path.display(),
+            utils::format_size(self.size)
+        )?;
+        let description = [self.vendor.as_deref(), self.model.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !description.is_empty() {
+            write!(f, ", {description}")?;
+        }
+        if self.removable {
+            write!(f, ", removable")?;
+        }
+        write!(f, ")")
+    }
+}
+
+struct Block {
+    name: String,
+    dev: (u64, u64),
+}
+
+struct Mount<'a> {
+    dev: Option<(u64, u64)>,
+    target: &'a str,
+    source: PathBuf,
+}
+
+impl<'a> Mount<'a> {
+    // See proc(5): the device number is field 3, the mount point field 5 and
+    // the mount source is the second field after the `-` separator
+    fn parse(line: &'a str) -> Option<Self> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let separator = fields.iter().position(|field| *field == "-")?;
+        Some(Self {
+            dev: parse_dev(fields.get(2)?),
+            target: fields.get(4)?,
+            source: canonical(fields.get(separator + 2)?),
+        })
+    }
+}
+
+/// The device itself, its partitions and anything layered on top of either
+/// (device mapper, md raid, ...)
+fn collect_backing(sysfs: &Path, backing: &mut Vec<Block>) -> Result<()> {
+    let Some(name) = sysfs.file_name().and_then(|name| name.to_str()) else {
+        bail!("Invalid sysfs entry {}", sysfs.display())
+    };
+    if backing.iter().any(|block| block.name == name) {
+        return Ok(());
+    }
+    if let Some(dev) = read_attr(sysfs, "dev")?.as_deref().and_then(parse_dev) {
+        backing.push(Block {
+            name: name.to_owned(),
+            dev,
+        });
+    }
+
+    for entry in fs::read_dir(sysfs)? {
+        let path = entry?.path();
+        if path.join("partition").exists() {
+            collect_backing(&path, backing)?;
+        }
+    }
+
+    let holders = sysfs.join("holders");
+    if holders.exists() {
+        for entry in fs::read_dir(holders)? {
+            let holder = entry?.file_name();
+            collect_backing(&Path::new(SYS_BLOCK).join(holder), backing)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_attr(sysfs: &Path, attr: &str) -> Result<Option<String>> {
+    match fs::read_to_string(sysfs.join(attr)) {
+        Ok(value) => {
+            Ok(Some(value.trim().to_owned()).filter(|value| !value.is_empty()))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!(e)
+            .context(format!("Failed to read {}", sysfs.join(attr).display()))),
+    }
+}
+
+fn parse_dev(dev: &str) -> Option<(u64, u64)> {
+    let (major, minor) = dev.split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn canonical(path: &str) -> PathBuf {
+    Path::new(path)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str) -> Device {
+        Device {
+            name: name.to_owned(),
+            path: Path::new("/dev").join(name),
+            size: 0,
+            vendor: None,
+            model: None,
+            removable: true,
+            sd_card: false,
+        }
+    }
+
+    #[test]
+    fn partition_paths() {
+        for (name, first) in [
+            ("sda", "/dev/sda1"),
+            ("mmcblk0", "/dev/mmcblk0p1"),
+            ("nvme0n1", "/dev/nvme0n1p1"),
+            ("loop0", "/dev/loop0p1"),
+        ] {
+            assert_eq!(device(name).partition(1), Path::new(first));
+        }
+        assert_eq!(device("sdb").partition(2), Path::new("/dev/sdb2"));
+        assert_eq!(device("mmcblk1").partition(2), Path::new("/dev/mmcblk1p2"));
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{Context as _, Error, Result, anyhow, bail};
+use argh::FromArgs;
+
+use crate::{
+    batch,
+    boot_config::{self, Edit, Setting},
+    host_keys,
+    image::BOOT_PARTITION,
+    mbr, resolve,
+    utils::{self, Prompt},
+    volume::{Fat, Volume},
+};
+
+const QEMU: &str = "qemu-system-aarch64";
+const KERNEL: &str = "kernel8.img";
+const RASPI3B_DTB: &str = "bcm2710-rpi-3-b.dtb";
+/// Where the guest's ssh is forwarded to, on an address of its own
+const SSH_PORT: u16 = 2222;
+/// Per-pi ssh configuration for the forwarded ports, in the app config
+const SSH_CONFIG_DIR: &str = "ssh_config.d";
+
+/// Boot an image file written by pi image --output in QEMU, so it can be
+/// managed like any other pi while it runs
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "emulate")]
+pub(crate) struct Args {
+    /// the pi the image was customized for
+    #[argh(positional)]
+    name: String,
+    /// the image file to boot, which is changed by running it
+    #[argh(option)]
+    image: PathBuf,
+    /// the machine to emulate, raspi3b or virt (which needs a kernel with
+    /// virtio drivers), defaults to raspi3b
+    #[argh(option, default = "Machine::Raspi3b")]
+    machine: Machine,
+    /// pad the image to the size QEMU needs without asking
+    #[argh(switch, short = 'y')]
+    yes: bool,
+}
+
+/// The QEMU machine the image is booted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Machine {
+    /// An emulated Raspberry Pi 3B, booted with the image's device tree
+    Raspi3b,
+    /// QEMU's generic board, with virtio disk and network
+    Virt,
+}
+
+impl Machine {
+    /// The root partition as the kernel sees it
+    fn root(self) -> &'static str {
+        match self {
+            Self::Raspi3b => "/dev/mmcblk0p2",
+            Self::Virt => "/dev/vda2",
+        }
+    }
+
+    /// The QEMU arguments for the machine, its disk and its network card
+    fn args(self, image: &str, dir: &Path) -> Vec<String> {
+        let kernel = dir.join(KERNEL).display().to_string();
+        match self {
+            Self::Raspi3b => vec![
+                "-M",
+                "raspi3b",
+                "-cpu",
+                "cortex-a53",
+                "-m",
+                "1G",
+                "-smp",
+                "4",
+                "-kernel",
+                &kernel,
+                "-dtb",
+                &dir.join(RASPI3B_DTB).display().to_string(),
+                "-drive",
+                &format!("file={image},if=sd,format=raw"),
+                "-device",
+                "usb-net,netdev=net0",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            Self::Virt => vec![
+                "-M",
+                "virt",
+                "-cpu",
+                "cortex-a72",
+                "-m",
+                "2G",
+                "-smp",
+                "4",
+                "-kernel",
+                &kernel,
+                "-drive",
+                &format!("file={image},if=none,format=raw,id=disk"),
+                "-device",
+                "virtio-blk-device,drive=disk",
+                "-device",
+                "virtio-net-device,netdev=net0",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+impl FromStr for Machine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raspi3b" => Ok(Self::Raspi3b),
+            "virt" => Ok(Self::Virt),
+            _ => bail!("Unknown machine {s} (expected raspi3b or virt)"),
+        }
+    }
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raspi3b => write!(f, "raspi3b"),
+            Self::Virt => write!(f, "virt"),
+        }
+    }
+}
+
+pub(crate) fn main(
+    Args {
+        name,
+        image,
+        machine,
+        yes,
+    }: Args,
+) -> Result<()> {
+    batch::validate_hostname(&name)?;
+    let path = image.to_str().ok_or_else(|| {
+        anyhow!("QEMU can't open {} (not UTF-8)", image.display())
+    })?;
+    // Commas separate QEMU's options, so they're doubled in file names
+    let path = path.replace(',', ",,");
+
+    // The kernel is booted directly, without the firmware to load it and
+    // read cmdline.txt
+    let tempdir = tempfile::tempdir()?;
+    let mut boot = Fat::open(&image, mbr::partition(&image, BOOT_PARTITION)?)?;
+    let mut files = vec![KERNEL];
+    if machine == Machine::Raspi3b {
+        files.push(RASPI3B_DTB);
+    }
+    for file in files {
+        let contents = boot
+            .read(file)?
+            .ok_or_else(|| anyhow!("The boot partition has no {file}"))?;
+        fs::write(tempdir.path().join(file), contents)?;
+    }
+    let cmdline = boot
+        .read_to_string("cmdline.txt")?
+        .ok_or_else(|| anyhow!("The boot partition has no cmdline.txt"))?;
+    drop(boot);
+    let cmdline = cmdline_for(&cmdline, machine)?;
+
+    if machine == Machine::Raspi3b {
+        pad_image(&image, yes)?;
+    }
+
+    let ip = address(&name)?;
+    forward_ssh(&name, ip)?;
+    resolve::set(&name, ip)?;
+    match host_keys::recorded(&name)? {
+        Some(keys) => host_keys::trust(ip, &keys)?,
+        None => println!(
+            "{name} wasn't imaged with known host keys, run pi register \
+             {name} once it's booted"
+        ),
+    }
+
+    println!(
+        "Booting {} as {name} on {ip} ({machine}), Ctrl-A X stops it",
+        image.display()
+    );
+    let status = Command::new(QEMU)
+        .arg("-nographic")
+        .args(machine.args(&path, tempdir.path()))
+        .args([
+            "-netdev",
+            &format!("user,id=net0,hostfwd=tcp:{ip}:{SSH_PORT}-:22"),
+            "-append",
+            &cmdline,
+        ])
+        .status()
+        .with_context(|| {
+            format!("Failed to run {QEMU} (is QEMU installed?)")
+        })?;
+    if !status.success() {
+        bail!("{QEMU} exited with {status}")
+    }
+    Ok(())
+}
+
+/// The image's kernel command line, rooted on the emulated disk and with its
+/// console on the emulated serial port
+fn cmdline_for(cmdline: &str, machine: Machine) -> Result<String> {
+    let set = [
+        Setting::cmdline("root", Some(machine.root()))?,
+        Setting::cmdline("console", Some("ttyAMA0,115200"))?,
+        Setting::cmdline("rw", None)?,
+    ];
+    // Resizing the root partition on first boot ends in a reboot, which
+    // would need the firmware QEMU doesn't run
+    let unset = [Setting::cmdline("init", None)?];
+    let edits = set
+        .iter()
+        .map(Edit::Set)
+        .chain(unset.iter().map(Edit::Unset))
+        .collect::<Vec<_>>();
+    Ok(boot_config::edit_cmdline(cmdline, &edits))
+}
+
+/// Grow the image to a power of two, the only sizes QEMU's SD cards come in
+///
+/// The image is padded where it is, rather than a copy, so that what the pi
+/// does while it runs is kept
+fn pad_image(image: &Path, yes: bool) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(image)
+        .with_context(|| format!("Failed to open {}", image.display()))?;
+    let length = file.metadata()?.len();
+    if length.is_power_of_two() {
+        return Ok(());
+    }
+    let padded = length.next_power_of_two();
+    if !yes {
+        if !utils::interactive() {
+            bail!("Refusing to pad {} without --yes", image.display())
+        }
+        prompt!(
+            "QEMU needs {} padded from {} to {}, and it stays that size. \
+             Continue? [y/N]: ",
+            image.display(),
+            utils::format_size(length),
+            utils::format_size(padded)
+        );
+        if utils::read_prompt(Prompt::No)?.is_no() {
+            bail!("Aborted emulation")
+        }
+    }
+    println!(
+        "Padding {} to {} for QEMU",
+        image.display(),
+        utils::format_size(padded)
+    );
+    file.set_len(padded)?;
+    Ok(())
+}
+
+/// The loopback address for `name`, the one it had before or one no other
+/// pi has
+fn address(name: &str) -> Result<Ipv4Addr> {
+    let db = resolve::load_db()?;
+    if let Some(ip) = db.get(name).filter(|ip| ip.is_loopback()) {
+        return Ok(*ip);
+    }
+    (2..=254)
+        .map(|n| Ipv4Addr::new(127, 0, 0, n))
+        .find(|ip| !db.values().any(|used| used == ip))
+        .ok_or_else(|| anyhow!("Every loopback address is taken"))
+}
+
+/// Have ssh (and so scp, sshfs, ...) connect to `ip` on the forwarded port,
+/// checking it against the host keys known for `ip`
+fn forward_ssh(name: &str, ip: Ipv4Addr) -> Result<()> {
+    let dir = utils::app_config()?.join(SSH_CONFIG_DIR);
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join(name),
+        format!("Host {ip}\n    Port {SSH_PORT}\n    HostKeyAlias {ip}\n"),
+    )?;
+
+    // Includes only apply to every host before the first Host line
+    let config = utils::home()?.join(".ssh").join("config");
+    let include = format!("Include {}/*", dir.display());
+    let contents = match fs::read_to_string(&config) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(anyhow!(e)
+                .context(format!("Failed to read {}", config.display())));
+        }
+    };
+    if contents.lines().any(|line| line.trim() == include) {
+        return Ok(());
+    }
+    if let Some(parent) = config.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&config, format!("{include}\n{contents}"))
+        .with_context(|| format!("Failed to write {}", config.display()))?;
+    println!(
+        "Added \"{include}\" to the top of {}, so ssh uses the ports pi \
+         emulate forwards",
+        config.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use anyhow::{Result, bail};
+
+use crate::{
+    systemd,
+    volume::{Mode, Volume},
+};
+
+/// Where the script goes on the boot partition
+pub(crate) const SCRIPT: &str = "pi-first-boot.sh";
+/// Where the script's output ends up on the pi
+pub(crate) const LOG: &str = "/var/log/pi-first-boot.log";
+const UNIT: &str = "pi-first-boot.service";
+
+/// Put `script` on the boot partition, to be run by the unit [enable]
+/// installs
+pub(crate) fn write_script(boot: &mut dyn Volume, script: &[u8]) -> Result<()> {
+    boot.write(SCRIPT, script, Mode::FILE)
+}
+
+/// Run the script once on first boot, once the network is up, with the boot
+/// partition mounted at `boot_dir`
+///
+/// The script and the unit remove themselves whether or not the script
+/// succeeds, its exit status is logged with its output
+pub(crate) fn enable(root: &mut dyn Volume, boot_dir: &str) -> Result<()> {
+    let script = format!("{boot_dir}/{SCRIPT}");
+    // systemd expands $$ to $, leaving $? for the shell
+    let unit = format!(
+        "\
+[Unit]
+Description=Run the script given to pi image --first-boot
+Wants=network-online.target
+After=network-online.target
+ConditionPathExists={script}
+
+[Service]
+Type=oneshot
+ExecStart=/bin/sh -c '/bin/bash {script} > {LOG} 2>&1; \
+echo \"Exited with status $$?\" >> {LOG}'
+ExecStartPost=/bin/rm -f {script}
+ExecStartPost=/bin/systemctl disable {UNIT}
+
+[Install]
+WantedBy=multi-user.target
+"
+    );
+    systemd::enable(root, UNIT, &unit)
+}
+
+/// The shell command running the script, for images that run it from
+/// cloud-init's runcmd rather than the unit [enable] installs
+pub(crate) fn command(boot_dir: &str) -> String {
+    let script = format!("{boot_dir}/{SCRIPT}");
+    format!(
+        "/bin/bash {script} > {LOG} 2>&1; echo \"Exited with status $?\" >> \
+         {LOG}; rm -f {script}"
+    )
+}
+
+/// `script` preceded by installing `packages`, for images without cloud-init
+/// to do it
+pub(crate) fn with_packages(
+    packages: &[String],
+    script: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    if packages.is_empty() {
+        return script.map(<[u8]>::to_vec);
+    }
+    let mut result = format!(
+        "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y \
+         {}\n",
+        packages.join(" ")
+    )
+    .into_bytes();
+    result.extend_from_slice(script.unwrap_or_default());
+    Some(result)
+}
+
+/// Check `package` is a Debian package name, optionally pinned to a version
+/// with `=`
+pub(crate) fn validate_package(package: &str) -> Result<()> {
+    let (name, version) = match package.split_once('=') {
+        Some((name, version)) => (name, Some(version)),
+        None => (package, None),
+    };
+    let valid = name.len() > 1
+        && name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c)
+        })
+        && version.is_none_or(|version| {
+            version.chars().next().is_some_and(|c| c.is_ascii_digit())
+                && version
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.:~".contains(c))
+        });
+    if !valid {
+        bail!("Invalid package {package} (as in vim, or vim=2:9.0.1378-2)")
+    }
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result, bail};
+use nix::fcntl::{PosixFadviseAdvice, posix_fadvise};
+use sha2::{Digest as _, Sha256};
+
+use crate::{payload::Payload, utils};
+
+// Large enough that the per-write overhead doesn't matter for SDCards
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// What actually ended up on the target
+#[derive(Debug)]
+pub(crate) struct Written {
+    pub(crate) length: u64,
+    pub(crate) sha256: String,
+}
+
+/// Write the decompressed image to `out`, then flush it all the way to the
+/// device
+///
+/// Progress is left out unless `show_progress` is set, as cards written in
+/// parallel would fight over the line
+pub(crate) fn write(
+    image: &Payload,
+    out: &mut File,
+    show_progress: bool,
+) -> Result<Written> {
+    let consumed = Cell::new(0);
+    let mut progress = Progress::new(image.length, show_progress);
+    let mut hasher = Sha256::new();
+    let mut length = 0;
+    image.stream(&consumed, |src| {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let read = fill(src, &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            out.write_all(&buf[..read])?;
+            hasher.update(&buf[..read]);
+            length += u64::try_from(read)?;
+            progress.update(consumed.get(), length);
+        }
+        Ok(())
+    })?;
+    progress.finish(length);
+
+    if show_progress {
+        prompt!("Syncing...");
+    }
+    out.flush()?;
+    out.sync_all()?;
+    if show_progress {
+        println!("Done");
+    }
+
+    Ok(Written {
+        length,
+        sha256: utils::hex(&hasher.finalize()),
+    })
+}
+
+/// Copy `length` bytes from `src` to `out` showing progress, for reading a
+/// card back into an image
+pub(crate) fn read(
+    src: &mut dyn Read,
+    length: u64,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut progress = Progress::new(length, true);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    while copied < length {
+        let want = usize::try_from((length - copied).min(CHUNK_SIZE as u64))?;
+        let read = fill(src, &mut buf[..want])?;
+        if read == 0 {
+            bail!("The card ended after {copied} bytes, expected {length}")
+        }
+        out.write_all(&buf[..read])?;
+        copied += u64::try_from(read)?;
+        progress.update(copied, copied);
+    }
+    progress.finish(copied);
+    Ok(())
+}
+
+/// Read `target` back and check it matches what [write] reported writing,
+/// re-reading the image to find the first bad byte if it doesn't
+pub(crate) fn verify(
+    image: &Payload,
+    target: &Path,
+    written: &Written,
+    show_progress: bool,
+) -> Result<()> {
+    let mut file = open_uncached(target)?;
+    let mut progress = Progress::new(written.length, show_progress);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut length = 0;
+    while length < written.length {
+        let want =
+            usize::try_from((written.length - length).min(CHUNK_SIZE as u64))?;
+        let read = fill(&mut file, &mut buf[..want])?;
+        if read == 0 {
+            bail!(
+                "{} is only {length} bytes long, expected at least {}",
+                target.display(),
+                written.length
+            )
+        }
+        hasher.update(&buf[..read]);
+        length += u64::try_from(read)?;
+        progress.update(length, length);
+    }
+    progress.finish(length);
+
+    if utils::hex(&hasher.finalize()) == written.sha256 {
+        return Ok(());
+    }
+
+    if show_progress {
+        prompt!("Checksum mismatch, locating the first bad byte...");
+    }
+    let offset = first_mismatch(image, target)?;
+    if show_progress {
+        println!("Done");
+    }
+    match offset {
+        Some(offset) => bail!(
+            "Verification failed: {} differs from the image at byte {offset} \
+             ({})",
+            target.display(),
+            utils::format_size(offset)
+        ),
+        None => bail!(
+            "Verification failed: {} read back differently to what was \
+             written, but matches the image now (flaky reader?)",
+            target.display()
+        ),
+    }
+}
+
+fn first_mismatch(image: &Payload, target: &Path) -> Result<Option<u64>> {
+    let mut file = open_uncached(target)?;
+    image.stream(&Cell::new(0), |src| {
+        let mut expected = vec![0; CHUNK_SIZE];
+        let mut actual = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let read = fill(src, &mut expected)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            let got = fill(&mut file, &mut actual[..read])?;
+            if let Some(index) = expected[..read]
+                .iter()
+                .zip(&actual[..got])
+                .position(|(expected, actual)| expected != actual)
+                .or((got < read).then_some(got))
+            {
+                return Ok(Some(offset + u64::try_from(index)?));
+            }
+            offset += u64::try_from(read)?;
+        }
+    })
+}
+
+/// Open `path` for reading, making sure reads come from the device rather
+/// than whatever is left in the page cache from writing it
+fn open_uncached(path: &Path) -> Result<File> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    posix_fadvise(&file, 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)?;
+    Ok(file)
+}
+
+/// Read until `buf` is full or the reader is exhausted so every write is a
+/// whole chunk
+fn fill(src: &mut dyn Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = src.read(&mut buf[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+/// Single line progress display
+///
+/// Progress through the operation is measured against `total` (the stored,
+/// possibly compressed, size of the source) while throughput is reported for
+/// the bytes that actually hit the target
+struct Progress {
+    total: u64,
+    shown: bool,
+    start: Instant,
+    last: Option<Instant>,
+}
+
+impl Progress {
+    fn new(total: u64, shown: bool) -> Self {
+        Self {
+            total,
+            shown,
+            start: Instant::now(),
+            last: None,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn update(&mut self, consumed: u64, written: u64) {
+        if !self.shown {
+            return;
+        }
+        let now = Instant::now();
+        if self.last.is_some_and(|last| now - last < REFRESH) {
+            return;
+        }
+        self.last = Some(now);
+
+        let elapsed = (now - self.start).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            written as f64 / elapsed
+        } else {
+            0.0
+        };
+        let fraction = if self.total == 0 {
+            1.0
+        } else {
+            (consumed as f64 / self.total as f64).min(1.0)
+        };
+        let eta = if fraction > 0.0 {
+            format_duration(elapsed * (1.0 - fraction) / fraction)
+        } else {
+            String::from("?")
+        };
+        print!(
+            "\r  {} ({:.0}%), {}/s, ETA {eta}    ",
+            utils::format_size(written),
+            fraction * 100.0,
+            utils::format_size(rate as u64)
+        );
+        let _ = std::io::stdout().flush();
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn finish(&self, written: u64) {
+        if !self.shown {
+            return;
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            written as f64 / elapsed
+        } else {
+            0.0
+        };
+        println!(
+            "\r  {} in {}, {}/s                    ",
+            utils::format_size(written),
+            format_duration(elapsed),
+            utils::format_size(rate as u64)
+        );
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m{secs:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{secs:02}s")
+    } else {
+        format!("{secs}s")
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write as _},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use command_ext::CommandExt as _;
+use tempfile::NamedTempFile;
+
+use crate::utils;
+
+const HOST_KEYS: &str = "host_keys";
+const KINDS: [&str; 3] = ["ed25519", "ecdsa", "rsa"];
+
+/// An SSH host key generated for a pi before it first boots
+#[derive(Debug)]
+pub(crate) struct HostKey {
+    pub(crate) kind: &'static str,
+    pub(crate) private: Vec<u8>,
+    /// The `.pub` file contents
+    pub(crate) public: String,
+    pub(crate) fingerprint: String,
+}
+
+impl HostKey {
+    /// The file name sshd expects the private key in, under /etc/ssh
+    pub(crate) fn file(&self) -> String {
+        format!("ssh_host_{}_key", self.kind)
+    }
+
+    /// The key in the form known_hosts expects it (type and base64 blob)
+    fn known_host(&self) -> String {
+        self.public
+            .split_whitespace()
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Generate a fresh set of host keys for `name`
+pub(crate) fn generate(name: &str) -> Result<Vec<HostKey>> {
+    let tempdir = tempfile::tempdir()?;
+    KINDS
+        .into_iter()
+        .map(|kind| {
+            let path = tempdir.path().join(kind);
+            Command::new("ssh-keygen")
+                .arg("-q")
+                .args(["-t", kind])
+                .args(["-N", ""])
+                .args(["-C", &format!("root@{name}")])
+                .arg("-f")
+                .arg(&path)
+                .check_status()?;
+            let public = path.with_extension("pub");
+            let fingerprint = Command::new("ssh-keygen")
+                .arg("-l")
+                .arg("-f")
+                .arg(&public)
+                .check_output()?
+                .split_whitespace()
+                .nth(1)
+                .ok_or_else(|| anyhow!("Unexpected ssh-keygen -l output"))?
+                .to_owned();
+            Ok(HostKey {
+                kind,
+                private: fs::read(&path)?,
+                public: fs::read_to_string(&public)?,
+                fingerprint,
+            })
+        })
+        .collect()
+}
+
+/// Stand-ins for the keys [generate] would make for `name`, showing where
+/// they'd go without generating any
+pub(crate) fn placeholders(name: &str) -> Vec<HostKey> {
+    KINDS
+        .into_iter()
+        .map(|kind| HostKey {
+            kind,
+            private: format!(
+                "<the {kind} host key pi image generates for {name}>\n"
+            )
+            .into_bytes(),
+            public: format!("<the public half of {name}'s {kind} host key>\n"),
+            fingerprint: String::new(),
+        })
+        .collect()
+}
+
+/// Remember the host keys baked into `name`'s image
+pub(crate) fn record(name: &str, keys: &[HostKey]) -> Result<()> {
+    let mut file = File::create(path(name)?)?;
+    for key in keys {
+        writeln!(file, "{}", key.known_host())?;
+    }
+    Ok(())
+}
+
+/// The host keys recorded when `name` was imaged, if it was imaged with
+/// them
+pub(crate) fn recorded(name: &str) -> Result<Option<Vec<String>>> {
+    match fs::read_to_string(path(name)?) {
+        Ok(contents) => Ok(Some(contents.lines().map(String::from).collect())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!(e).context(format!(
+            "Failed to read the recorded host keys for {name}"
+        ))),
+    }
+}
+
+/// Replace whatever known_hosts has for `ip` with `keys`
+pub(crate) fn trust(ip: Ipv4Addr, keys: &[String]) -> Result<()> {
+    trust_in(&known_hosts()?, ip, keys)
+}
+
+fn trust_in(path: &Path, ip: Ipv4Addr, keys: &[String]) -> Result<()> {
+    forget_in(path, ip)?;
+    let mut contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => Err(e)?,
+    };
+    if !contents.is_empty() && !contents.ends_with(b"\n") {
+        contents.push(b'\n');
+    }
+    for key in keys {
+        contents.extend_from_slice(format!("{ip} {key}\n").as_bytes());
+    }
+
+    // Written alongside and renamed over, so known_hosts is never left
+    // half written
+    let dir = path.parent().expect("known_hosts is in ~/.ssh");
+    fs::create_dir_all(dir)?;
+    let mut new = NamedTempFile::new_in(dir)?;
+    new.write_all(&contents)?;
+    if let Ok(metadata) = fs::metadata(path) {
+        new.as_file().set_permissions(metadata.permissions())?;
+    }
+    let _ = new
+        .persist(path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Whether known_hosts has a key for `ip`, hashed or not
+pub(crate) fn is_known(ip: Ipv4Addr) -> Result<bool> {
+    let path = known_hosts()?;
+    if !path.exists() {
+        return Ok(false);
+    }
+    let output = Command::new("ssh-keygen")
+        .arg("-F")
+        .arg(ip.to_string())
+        .arg("-f")
+        .arg(&path)
+        .output()?;
+    match output.status.code() {
+        // Wildcard @cert-authority and @revoked lines match too, they
+        // aren't keys for the pi
+        Some(0) => Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| !line.is_empty() && !line.starts_with(['#', '@']))),
+        Some(1) => Ok(false),
+        _ => bail!(
+            "ssh-keygen couldn't search {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// Remove the keys known_hosts has for `ip`, leaving everything else as it
+/// was
+pub(crate) fn forget(ip: Ipv4Addr) -> Result<()> {
+    forget_in(&known_hosts()?, ip)
+}
+
+fn forget_in(path: &Path, ip: Ipv4Addr) -> Result<()> {
+    if path.exists() {
+        // ssh-keygen writes the new file alongside and renames it over
+        let _ = Command::new("ssh-keygen")
+            .arg("-R")
+            .arg(ip.to_string())
+            .arg("-f")
+            .arg(path)
+            .check_output()?;
+    }
+    Ok(())
+}
+
+fn known_hosts() -> Result<PathBuf> {
+    Ok(utils::home()?.join(".ssh").join("known_hosts"))
+}
+
+fn path(name: &str) -> Result<PathBuf> {
+    let dir = utils::app_config()?.join(HOST_KEYS);
+    if !dir.exists() {
+        fs::create_dir(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    Ok(dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trust_keeps_the_rest_of_known_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_hosts");
+        let ip = Ipv4Addr::new(192, 168, 1, 2);
+        fs::write(
+            &path,
+            "# managed by hand\n\
+             \n\
+             192.168.1.2 ssh-ed25519 OLD\n\
+             |1|AAECAwQFBgcICQoLDA0ODxAREhM=|AAAAAAAAAAAAAAAAAAAAAAAAAAA= ssh-ed25519 HASHED\n\
+             @cert-authority *.lan ssh-rsa CA\n\
+             10.0.0.1 ssh-rsa OTHER",
+        )
+        .unwrap();
+        trust_in(&path, ip, &["ssh-ed25519 NEW".to_owned()]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# managed by hand\n\
+             \n\
+             |1|AAECAwQFBgcICQoLDA0ODxAREhM=|AAAAAAAAAAAAAAAAAAAAAAAAAAA= ssh-ed25519 HASHED\n\
+             @cert-authority *.lan ssh-rsa CA\n\
+             10.0.0.1 ssh-rsa OTHER\n\
+             192.168.1.2 ssh-ed25519 NEW\n"
+        );
+    }
+
+    #[test]
+    fn trust_creates_known_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".ssh").join("known_hosts");
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        trust_in(
+            &path,
+            ip,
+            &["ssh-ed25519 A".to_owned(), "ssh-rsa B".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "10.0.0.1 ssh-ed25519 A\n10.0.0.1 ssh-rsa B\n"
+        );
+    }
+}
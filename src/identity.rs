@@ -1,6 +1,9 @@
-use std::{fs, marker::PhantomData, path::PathBuf};
+use std::{
+    fs, marker::PhantomData, os::unix::fs::DirBuilderExt as _, path::PathBuf,
+    process::Command,
+};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 
 use crate::utils;
 
@@ -48,6 +51,29 @@ impl Identity<Unknown> {
         }
         Err(self)
     }
+
+    pub(crate) fn generate(self, name: &str) -> Result<Identity<Created>> {
+        if let Some(ssh) = self.private.parent()
+            && !ssh.exists()
+        {
+            fs::DirBuilder::new().mode(0o700).create(ssh)?;
+        }
+        let success = Command::new("ssh-keygen")
+            .args(["-t", "rsa"]) // Key format
+            .args(["-N", ""]) // No password
+            .args(["-C", &format!("Auto-generated key for {name}.local")]) // Comment
+            .arg("-f")
+            .arg(&self.private) // Key location
+            .status()?
+            .success();
+        if !success {
+            bail!("ssh-keygen failed")
+        }
+        Ok(self
+            .exists()
+            .ok()
+            .expect("Identity should exist because we just created it"))
+    }
 }
 
 impl Identity<Created> {
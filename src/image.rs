@@ -1,169 +1,728 @@
 use std::{
-    fs::{self, File},
-    io::{Read as _, Write as _},
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read as _, Seek as _, SeekFrom},
+    net::IpAddr,
     path::{Path, PathBuf},
     process::Command,
-    sync::LazyLock,
+    sync::{Mutex, PoisonError},
 };
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context as _, Result, anyhow, bail};
 use argh::FromArgs;
 use command_ext::CommandExt;
 use nix::unistd::Uid;
-#[cfg(debug_assertions)]
-use sliding_windows as _;
 
-use crate::utils::{self, Prompt};
+use crate::{
+    batch,
+    boot_config::{self, Setting},
+    cloud_init::{self, UserData},
+    crypt,
+    device::Device,
+    first_boot, flash,
+    host_keys::{self, HostKey},
+    identity::Identity,
+    images, inventory,
+    locale::Locale,
+    mbr, networks,
+    os_release::{OsProfile, OsRelease},
+    overlay::Overlay,
+    payload::{Compression, Payload},
+    plan::Plan,
+    profile::{self, Profile},
+    static_ip::{self, Address, StaticIp},
+    systemd, users,
+    utils::{self, Prompt},
+    volume::{Changes, CopyOnWrite, Ext, Fat, Mode, Mounted, Volume},
+    wifi::{self, Key, Network, Security, Ssid},
+};
 
-const SDPATH: &str = "/dev/sda";
-const BOOT_PARTITION: &str = "/dev/sda1";
-const ROOT_PARTITION: &str = "/dev/sda2";
+// Started on first boot of images that don't ship host keys, replacing any
+// baked in
+const REGENERATE_HOST_KEYS: &str = "regenerate_ssh_host_keys.service";
+/// The uid of the image's first user, which userconf renames
+const FIRST_UID: u32 = 1000;
 
-const WIFI: &str = "country=GB\n\
-                    ctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\n\
-                    update_config=1\n\
-                    \n\
-                    network={\n\
-                       ssid=\"{ssid}\"\n\
-                       psk=\"{pass}\"\n\
-                       }";
-// Password hash generated using: echo "raspberry" | openssl passwd -6 -stdin
-const USERCONF: &str = "pi:$6$0tJ78aVORQEk8spk$LT66yvA.gwx7jGxJFBSoQF7GTeJDzrqJNuQWdHg8y05917vCWHVqb9ECH0EDspGT7zJz81Z8Rs6vD0Cq3Kthb1";
+pub(crate) const BOOT_PARTITION: u32 = 1;
+pub(crate) const ROOT_PARTITION: u32 = 2;
 
 #[cfg(debug_assertions)]
-static RASPBIAN: LazyLock<Result<Vec<u8>>> = LazyLock::new(|| {
-    Ok(File::open(env!("IMAGE"))?
-        .bytes()
-        .collect::<Result<Vec<_>, _>>()?)
-});
+fn raspbian() -> Result<Payload> {
+    match option_env!("IMAGE") {
+        Some(image) => Payload::from_file(image),
+        None => bail!(
+            "This build doesn't have a default image (res/raspbian.img was \
+             missing at compile time), use --image"
+        ),
+    }
+}
 
 #[cfg(not(debug_assertions))]
-static RASPBIAN: LazyLock<Result<Vec<u8>>> = LazyLock::new(|| {
-    use std::env;
-
-    use sliding_windows::{IterExt as _, Storage};
-
-    const MARKER: &[u8] = "PI_END".as_bytes();
-
-    let bytes = File::open(env::current_exe()?)?
-        .bytes()
-        .collect::<Result<Vec<_>, _>>()?;
+fn raspbian() -> Result<Payload> {
+    Payload::embedded(std::env::current_exe()?)
+}
 
-    let mut storage: Storage<(usize, u8)> = Storage::new(MARKER.len());
-    let end = bytes
-        .iter()
-        .copied()
-        .enumerate()
-        .rev()
-        .sliding_windows(&mut storage)
-        .map(|window| {
-            let mut window = window.iter().copied().collect::<Vec<_>>();
-            window.reverse();
-            let start = window[0].0;
-            let bytes =
-                window.into_iter().map(|(_, byte)| byte).collect::<Vec<_>>();
-            (start, bytes)
-        })
-        .find(|(_, window)| window == MARKER)
-        .ok_or_else(|| anyhow!("Couldn't find the end of the embedded ISO"))?
-        .0;
-    let start = usize::try_from(u64::from_le_bytes(
-        <[u8; 8]>::try_from(
-            bytes
-                .iter()
-                .copied()
-                .skip(end + MARKER.len())
-                .collect::<Vec<_>>(),
-        )
-        .map_err(|v| {
-            anyhow!(
-                "Expected to find 8 bytes after the end marker, got {}",
-                v.len()
-            )
-        })?,
-    ))?;
-    Ok(bytes
-        .into_iter()
-        .skip(start)
-        .enumerate()
-        .take_while(|(index, _)| *index < end - start)
-        .map(|(_, byte)| byte)
-        .collect::<Vec<_>>())
-});
-
-/// Flash a Raspbian image onto the system's SDCard device, enable ssh access
-/// and set the hostname
-#[derive(Debug, FromArgs)]
+/// Flash a Raspbian image onto an SDCard (or into an image file), enable ssh
+/// access and set the hostname
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default, FromArgs)]
 #[argh(subcommand, name = "image")]
 pub(crate) struct Args {
-    /// the hostname for the new image
+    /// the hostname for the new image, prompted for if not given here or in
+    /// the profile. A pattern like sensor-{01..20} images a card for each
+    /// name, as the cards are inserted
     #[argh(positional)]
-    name: String,
+    name: Option<String>,
+    /// the device to write to (e.g. /dev/sdb or mmcblk0), chosen from the
+    /// removable devices if not given
+    #[argh(option)]
+    device: Option<String>,
+    /// the image to write, either a path to an image file or the name of a
+    /// cached image (see pi images), defaults to the embedded image
+    #[argh(option)]
+    image: Option<String>,
+    /// write a customized image file instead of an SDCard, which doesn't
+    /// need root
+    #[argh(option)]
+    output: Option<PathBuf>,
+    /// read the SDCard back after writing it and check it matches the image
+    #[argh(switch)]
+    verify: bool,
+    /// print the manifest of the image that would be written and exit
+    #[argh(switch)]
+    info: bool,
+    /// show the files the customization would create or change instead,
+    /// without writing anything or asking for passwords (which doesn't need
+    /// root). Compressed images are unpacked to a scratch file next to the
+    /// output (or in ~/.pi) to be read
+    #[argh(switch)]
+    dry_run: bool,
+    /// overwrite the target without asking
+    #[argh(switch, short = 'y')]
+    yes: bool,
+    /// a saved wifi network to connect to (see pi networks), can be repeated
+    #[argh(option)]
+    network: Vec<String>,
+    /// a WPA2 wifi network to connect to, in addition to any saved networks
+    #[argh(option)]
+    ssid: Option<String>,
+    /// a file containing the password for --ssid
+    #[argh(option)]
+    psk_file: Option<PathBuf>,
+    /// don't set up wifi, for pis that are only on ethernet
+    #[argh(switch)]
+    no_wifi: bool,
+    /// the wifi regulatory country code, defaults to GB
+    #[argh(option)]
+    country: Option<String>,
+    /// the user to create, defaults to pi
+    #[argh(option)]
+    user: Option<String>,
+    /// a file containing the user's password, prompted for if not given
+    #[argh(option)]
+    password_file: Option<PathBuf>,
+    /// a static address with its prefix length (e.g. 192.168.1.20/24), at
+    /// most one IPv4 and one IPv6
+    #[argh(option)]
+    address: Vec<Address>,
+    /// the gateway for a static address, IPv6 gateways are only used on
+    /// NetworkManager images
+    #[argh(option)]
+    gateway: Vec<IpAddr>,
+    /// a DNS server for a static address, can be repeated
+    #[argh(option)]
+    dns: Vec<IpAddr>,
+    /// the interface to give the static address, defaults to eth0
+    #[argh(option)]
+    interface: Option<String>,
+    /// the timezone (e.g. Europe/London)
+    #[argh(option)]
+    timezone: Option<String>,
+    /// the locale (e.g. en_GB.UTF-8)
+    #[argh(option)]
+    locale: Option<String>,
+    /// the keyboard layout (e.g. gb)
+    #[argh(option)]
+    keyboard: Option<String>,
+    /// a script to run (with bash, as root) once on first boot, its output
+    /// is left in /var/log/pi-first-boot.log
+    #[argh(option)]
+    first_boot: Option<PathBuf>,
+    /// a config.txt setting (e.g. dtoverlay=vc4-kms-v3d), or a kernel
+    /// argument prefixed with cmdline:, can be repeated
+    #[argh(option)]
+    boot_config: Vec<Setting>,
+    /// how the image is configured, raspbian or cloud-init (e.g. for Ubuntu
+    /// Server), detected from the image if not given
+    #[argh(option)]
+    os: Option<OsProfile>,
+    /// a package to install on first boot, can be repeated
+    #[argh(option)]
+    package: Vec<String>,
+    /// a TOML file describing the customization, the other options take
+    /// precedence over it
+    #[argh(option)]
+    profile: Option<PathBuf>,
 }
 
-pub(crate) fn main(Args { name }: Args) -> Result<()> {
-    if !Uid::effective().is_root() {
-        bail!("The image subcommand requires root permissions")
+impl Args {
+    /// Options writing `name` into the image file `output` without wifi,
+    /// with everything not given here coming from the profile
+    pub(crate) fn wired(
+        name: String,
+        output: PathBuf,
+        image: Option<String>,
+        user: Option<String>,
+        password_file: Option<PathBuf>,
+        profile: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            name: Some(name),
+            output: Some(output),
+            image,
+            user,
+            password_file,
+            profile,
+            no_wifi: true,
+            yes: true,
+            ..Self::default()
+        }
+    }
+}
+
+pub(crate) fn main(args: Args) -> Result<()> {
+    let profile = match &args.profile {
+        Some(profile) => Profile::load(profile)?,
+        None => Profile::default(),
+    };
+
+    let image = source(args.image.as_deref().or(profile.image.as_deref()))?;
+    if args.info {
+        return info(&image);
     }
 
-    if !PathBuf::from(SDPATH).exists() {
-        bail!("{SDPATH} doesn't exist. Is the SDCard plugged in?")
+    let name = utils::ask(
+        args.name.clone().or_else(|| profile.hostname.clone()),
+        "Hostname",
+        "the positional argument or a profile",
+    )?;
+    if let Some(names) = batch::expand(&name)? {
+        if args.dry_run {
+            bail!("--dry-run shows one pi, use one of the names in {name}")
+        }
+        return batch(&name, names, &image, args, profile);
     }
 
-    prompt!(
-        "WARNING: This command will overwrite the SDCard in {SDPATH}. Continue? [y/N]: "
+    let target = if let Some(output) = &args.output {
+        if args.device.is_some() {
+            bail!("--device and --output can't be used together")
+        }
+        Target::File(output.clone())
+    } else {
+        if !args.dry_run {
+            ensure_root()?;
+        }
+        let device = Device::select(args.device.as_deref())?;
+        device.ensure_unused()?;
+        Target::Device(device)
+    };
+    if args.dry_run {
+        return dry_run(&name, &image, &target, args, profile);
+    }
+
+    if target.exists() && !args.yes {
+        confirm_overwrite(&target)?;
+    }
+
+    let verify = args.verify;
+    let settings = Settings::new(args, profile)?;
+    let custom = settings.for_pi(&name)?;
+
+    verify_checksum(&image)?;
+    println!("Imaging {} (this may take a while)...", describe(&image)?);
+    write(&image, &target, verify, true)?;
+
+    customize(&target, &custom)?;
+    inventory::record(&name, &image.summary(), &settings.user, None)
+}
+
+/// Image a card for each of `names` as they're inserted, `pattern` being
+/// where the names came from
+fn batch(
+    pattern: &str,
+    names: Vec<String>,
+    image: &Payload,
+    args: Args,
+    profile: Profile,
+) -> Result<()> {
+    if args.output.is_some() || args.device.is_some() {
+        bail!(
+            "--output and --device can't be used with a hostname pattern, \
+             cards are imaged as they're inserted"
+        )
+    }
+    ensure_root()?;
+    if !args.yes {
+        confirm_overwrite("every card inserted from now on")?;
+    }
+
+    let verify = args.verify;
+    let settings = Settings::new(args, profile)?;
+    if settings.static_ip.is_some() {
+        bail!("Every pi in a batch would get the same static address")
+    }
+
+    verify_checksum(image)?;
+    println!(
+        "Imaging {} pis with {} ({})",
+        names.len(),
+        image.summary(),
+        describe(image)?
     );
+    // Cards are written in parallel, but keys are generated and images
+    // customized one card at a time so the output for each stays together
+    let exclusive = Mutex::new(());
+    let exclusive = || exclusive.lock().unwrap_or_else(PoisonError::into_inner);
+    batch::run(names, |name, device| {
+        let custom = {
+            let _exclusive = exclusive();
+            settings.for_pi(name)?
+        };
+        let target = Target::Device(device.clone());
+        write(image, &target, verify, false)?;
+
+        let _exclusive = exclusive();
+        println!("[{name}] Written to {}", device.path.display());
+        customize(&target, &custom)?;
+        inventory::record(name, &image.summary(), &settings.user, Some(pattern))
+    })
+}
+
+/// Customize `image` for `name` without writing to it and show what would
+/// change, leaving `target` alone
+fn dry_run(
+    name: &str,
+    image: &Payload,
+    target: &Target,
+    args: Args,
+    profile: Profile,
+) -> Result<()> {
+    let settings = Settings::new(args, profile)?;
+    let custom = settings.for_pi(name)?;
+
+    verify_checksum(image)?;
+    // Uncompressed images are read where they are, others have to be
+    // unpacked first. That's as big as the image, too big for a tmpfs, so
+    // it's done next to the output, or in the app config for a card
+    let _tempdir;
+    let dry_run = if let Some((path, offset)) = image.in_place()? {
+        DryRun::new(path.to_owned(), offset)
+    } else {
+        let scratch_dir = match target {
+            Target::File(output) => output
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_owned(),
+            Target::Device(_) | Target::DryRun(_) => utils::app_config()?,
+        };
+        let tempdir =
+            tempfile::tempdir_in(&scratch_dir).with_context(|| {
+                format!(
+                    "Failed to make a scratch image in {}",
+                    scratch_dir.display()
+                )
+            })?;
+        let scratch = tempdir.path().join("image.img");
+        _tempdir = tempdir;
+        println!(
+            "Unpacking {} so it can be read (this may take a while)...",
+            describe(image)?
+        );
+        write(image, &Target::File(scratch.clone()), false, true)?;
+        DryRun::new(scratch, 0)
+    };
+    let dry_run = Target::DryRun(dry_run);
+    customize(&dry_run, &custom)?;
+
+    println!("Changes that would be made to {target}:");
+    if let Target::DryRun(dry_run) = dry_run {
+        dry_run.plan.into_inner().print();
+    }
+    Ok(())
+}
+
+pub(crate) fn ensure_root() -> Result<()> {
+    if !Uid::effective().is_root() {
+        bail!("Writing to an SDCard requires root permissions")
+    }
+    Ok(())
+}
+
+pub(crate) fn confirm_overwrite(target: impl fmt::Display) -> Result<()> {
+    if !utils::interactive() {
+        bail!("Refusing to overwrite {target} without --yes")
+    }
+    prompt!("WARNING: This command will overwrite {target}. Continue? [y/N]: ");
     if utils::read_prompt(Prompt::No)?.is_no() {
         bail!("Aborted image operation")
     }
+    Ok(())
+}
 
-    prompt!("Wifi SSID: ");
-    let ssid = utils::read_line()?;
+/// The wifi networks to join
+///
+/// Saved networks named on the command line replace those from the profile,
+/// as does an SSID. Only prompts for an SSID if no networks were given at all,
+/// and doesn't prompt for its password on a dry run
+fn networks(
+    names: &[String],
+    ssid: Option<String>,
+    psk_file: Option<PathBuf>,
+    profile: &profile::Wifi,
+    dry_run: bool,
+) -> Result<Vec<Network>> {
+    let names = if names.is_empty() {
+        &profile.networks
+    } else {
+        names
+    };
+    let mut networks = names
+        .iter()
+        .map(|name| networks::open(name))
+        .collect::<Result<Vec<_>>>()?;
 
-    prompt!("Wifi Password: ");
-    let password = utils::read_line()?;
+    let ssid = ssid.or_else(|| profile.ssid.clone());
+    if ssid.is_none() && !networks.is_empty() {
+        return Ok(networks);
+    }
+    let ssid = Ssid::parse(&utils::ask(
+        ssid,
+        "Wifi SSID",
+        "--ssid, --network or a profile",
+    )?)?;
+    let password = match psk_file {
+        Some(file) => Some(profile::read_secret(&file)?),
+        None => profile.psk()?,
+    };
+    let password = password.or_else(|| {
+        dry_run.then(|| String::from("<the wifi password pi image asks for>"))
+    });
+    let password = utils::ask_secret(
+        password,
+        "Wifi Password",
+        "--psk-file or a profile",
+    )?;
+    networks.push(Network {
+        key: Key::new(Security::Wpa2, &ssid, Some(&password))?,
+        ssid,
+        priority: 0,
+        hidden: false,
+    });
+    Ok(networks)
+}
 
-    prompt!("Imaging (this may take a while)...");
-    File::create(SDPATH)?
-        .write_all(RASPBIAN.as_ref().map_err(|e| anyhow!(e))?)?;
-    println!("Done");
+/// What every pi imaged by one command gets
+#[derive(Debug)]
+struct Settings {
+    /// Detected from the image if not given
+    os: Option<OsProfile>,
+    user: String,
+    /// SHA-512 crypt hash of the user's password
+    password_hash: String,
+    country: String,
+    networks: Vec<Network>,
+    static_ip: Option<StaticIp>,
+    locale: Locale,
+    /// The contents of the script to run on first boot
+    first_boot: Option<Vec<u8>>,
+    packages: Vec<String>,
+    /// Applied after the settings the customization itself needs
+    boot_config: Vec<Setting>,
+    /// Nothing is generated or recorded locally for a dry run
+    dry_run: bool,
+}
 
-    prompt!("Setting up network & ssh...");
-    with(BOOT_PARTITION, |path| {
-        drop(File::create(path.join("ssh"))?);
-
-        let mut wpa_supplicant =
-            File::create(path.join("wpa_supplicant.conf"))?;
-        write!(
-            wpa_supplicant,
-            "{}",
-            WIFI.replace("{ssid}", &ssid).replace("{pass}", &password)
+impl Settings {
+    /// Gather everything from the command line and profile that isn't
+    /// specific to one pi, prompting for anything missing
+    fn new(
+        Args {
+            network,
+            ssid,
+            psk_file,
+            no_wifi,
+            country,
+            user,
+            password_file,
+            address,
+            gateway,
+            dns,
+            interface,
+            timezone,
+            locale,
+            keyboard,
+            first_boot,
+            boot_config,
+            os,
+            package,
+            dry_run,
+            ..
+        }: Args,
+        profile: Profile,
+    ) -> Result<Self> {
+        let user = user
+            .or_else(|| profile.user.name.clone())
+            .unwrap_or_else(|| String::from(users::DEFAULT_USER));
+        users::validate(&user)?;
+        let password_hash =
+            password_hash(&user, password_file, &profile.user, dry_run)?;
+
+        let networks = if no_wifi {
+            Vec::new()
+        } else {
+            networks(&network, ssid, psk_file, &profile.wifi, dry_run)?
+        };
+        let static_ip =
+            static_ip(interface, &address, &gateway, dns, profile.network)?;
+        let locale = Locale {
+            timezone: timezone.or(profile.locale.timezone),
+            lang: locale.or(profile.locale.lang),
+            keyboard: keyboard.or(profile.locale.keyboard),
+        };
+        locale.validate()?;
+        let first_boot = read_script(first_boot.or(profile.first_boot))?;
+        let packages = if package.is_empty() {
+            profile.packages
+        } else {
+            package
+        };
+        for package in &packages {
+            first_boot::validate_package(package)?;
+        }
+        let boot_config = [profile.boot.config, boot_config].concat();
+        let _ = boot_config::edits(&boot_config, &[])?;
+        let country = wifi::country(
+            country
+                .as_deref()
+                .or(profile.wifi.country.as_deref())
+                .unwrap_or(wifi::DEFAULT_COUNTRY),
         )?;
 
-        let cmdline = path.join("cmdline.txt");
-        let contents = fs::read_to_string(&cmdline)?;
-        let contents = contents.trim();
-        let mut cmdline = File::create(cmdline)?;
-        write!(cmdline, "{contents} ipv6.disable=1")?;
+        Ok(Self {
+            os: os.or(profile.os),
+            user,
+            password_hash,
+            country,
+            networks,
+            static_ip,
+            locale,
+            first_boot,
+            packages,
+            boot_config,
+            dry_run,
+        })
+    }
 
-        let mut userconf = File::create(path.join("userconf"))?;
-        write!(userconf, "{USERCONF}")?;
+    /// The customization for the pi called `name`, generating its keys
+    /// unless it's a dry run
+    fn for_pi(&self, name: &str) -> Result<Customization<'_>> {
+        batch::validate_hostname(name)?;
+        Ok(Customization {
+            hostname: name.to_owned(),
+            authorized_key: authorized_key(name, self.dry_run)?,
+            host_keys: if self.dry_run {
+                host_keys::placeholders(name)
+            } else {
+                host_keys::generate(name)?
+            },
+            overlays: Overlay::find(name)?,
+            settings: self,
+        })
+    }
+}
+
+/// What to change in the freshly written image
+#[derive(Debug)]
+struct Customization<'a> {
+    hostname: String,
+    /// The public key of the pi's identity, for the user's authorized_keys
+    authorized_key: String,
+    host_keys: Vec<HostKey>,
+    overlays: Vec<Overlay>,
+    settings: &'a Settings,
+}
+
+/// Apply `custom` to the image just written to `target`
+fn customize(target: &Target, custom: &Customization<'_>) -> Result<()> {
+    let os = target.with_partition(ROOT_PARTITION, OsRelease::read)?;
+    let profile = match custom.settings.os {
+        Some(profile) => profile,
+        None => target.with_partition(BOOT_PARTITION, |boot| {
+            Ok(if boot.read(cloud_init::USER_DATA)?.is_some() {
+                OsProfile::CloudInit
+            } else {
+                OsProfile::Raspbian
+            })
+        })?,
+    };
+    println!("Customizing {os} ({profile})");
+    match profile {
+        OsProfile::Raspbian => customize_raspbian(target, custom, &os)?,
+        OsProfile::CloudInit => customize_cloud_init(target, custom)?,
+    }
+
+    for overlay in &custom.overlays {
+        prompt!("Applying overlay {}...", overlay.dir().display());
+        let copied = target
+            .with_partition(ROOT_PARTITION, |root| overlay.apply(root))?;
+        println!("Done, {copied} copied");
+    }
+
+    if custom.settings.first_boot.is_some() {
+        println!(
+            "The first boot script's output will be in {} (pi cat {} {0})",
+            first_boot::LOG,
+            custom.hostname
+        );
+    }
+
+    if !custom.settings.dry_run {
+        users::set(&custom.hostname, &custom.settings.user)?;
+        host_keys::record(&custom.hostname, &custom.host_keys)?;
+        for key in &custom.host_keys {
+            println!("{} host key: {}", key.kind, key.fingerprint);
+        }
+    }
+
+    Ok(())
+}
+
+/// Customize Raspberry Pi OS through the boot partition and by editing the
+/// root partition directly
+fn customize_raspbian(
+    target: &Target,
+    custom: &Customization<'_>,
+    os: &OsRelease,
+) -> Result<()> {
+    let network_manager = os.uses_network_manager();
+
+    prompt!("Setting up network & ssh...");
+    target.with_partition(BOOT_PARTITION, |boot| {
+        write_boot(boot, custom.settings, network_manager)
+    })?;
+    let static_ip = custom.settings.static_ip.as_ref();
+    if network_manager {
+        let wifi_ip = static_ip.filter(|ip| ip.is_wifi());
+        let mut connections =
+            wifi::network_manager(&custom.settings.networks, wifi_ip);
+        if let Some(ip) = static_ip.filter(|ip| !ip.is_wifi()) {
+            connections.push(ip.ethernet());
+        }
+        target.with_partition(ROOT_PARTITION, |root| {
+            for (file, connection) in connections {
+                // NetworkManager ignores connections anyone else can read
+                root.write(
+                    &format!("{}/{file}", wifi::SYSTEM_CONNECTIONS),
+                    connection.as_bytes(),
+                    Mode::root(0o600),
+                )?;
+            }
+            Ok(())
+        })?;
+    } else if let Some(ip) = static_ip {
+        target.with_partition(ROOT_PARTITION, |root| ip.write_dhcpcd(root))?;
+    }
+    println!("Done");
+
+    prompt!("Setting hostname to {}...", custom.hostname);
+    target.with_partition(ROOT_PARTITION, |root| {
+        root.write("etc/hostname", custom.hostname.as_bytes(), Mode::FILE)?;
+
+        let hosts = root
+            .read_to_string("etc/hosts")?
+            .ok_or_else(|| anyhow!("The root partition has no /etc/hosts"))?
+            .replace("raspberrypi", &custom.hostname);
+        root.write("etc/hosts", hosts.as_bytes(), Mode::FILE)?;
 
         Ok(())
     })?;
     println!("Done");
 
-    prompt!("Setting hostname to {name}...");
-    with(ROOT_PARTITION, |path| {
-        let mut hostname = File::create(path.join("etc/hostname"))?;
-        write!(hostname, "{name}")?;
+    prompt!("Installing ssh keys...");
+    target.with_partition(ROOT_PARTITION, |root| install_keys(root, custom))?;
+    println!("Done");
 
-        let hosts = path.join("etc/hosts");
-        let contents =
-            fs::read_to_string(&hosts)?.replace("raspberrypi", &name);
-        let mut hosts = File::create(hosts)?;
-        write!(hosts, "{contents}")?;
+    if !custom.settings.locale.is_empty() {
+        prompt!("Setting locale...");
+        target.with_partition(ROOT_PARTITION, |root| {
+            custom.settings.locale.apply(root)
+        })?;
+        println!("Done");
+    }
+
+    // Without cloud-init to install packages they're installed by the first
+    // boot script
+    let script = first_boot::with_packages(
+        &custom.settings.packages,
+        custom.settings.first_boot.as_deref(),
+    );
+    if let Some(script) = script {
+        prompt!("Installing first boot script...");
+        target.with_partition(BOOT_PARTITION, |boot| {
+            first_boot::write_script(boot, &script)
+        })?;
+        target.with_partition(ROOT_PARTITION, |root| {
+            first_boot::enable(root, os.boot_dir())
+        })?;
+        println!("Done");
+    }
 
+    Ok(())
+}
+
+/// Customize an image that configures itself with cloud-init, which only
+/// needs files on the boot partition
+fn customize_cloud_init(
+    target: &Target,
+    custom: &Customization<'_>,
+) -> Result<()> {
+    let settings = custom.settings;
+    let user_data = UserData {
+        hostname: &custom.hostname,
+        user: &settings.user,
+        password_hash: &settings.password_hash,
+        authorized_key: &custom.authorized_key,
+        host_keys: &custom.host_keys,
+        locale: &settings.locale,
+        packages: &settings.packages,
+        first_boot: settings.first_boot.is_some(),
+    };
+    let network_config = cloud_init::network_config(
+        &settings.country,
+        &settings.networks,
+        settings.static_ip.as_ref(),
+    )?;
+
+    prompt!("Writing cloud-init configuration...");
+    target.with_partition(BOOT_PARTITION, |boot| {
+        let config = boot_config(settings, None)?;
+        boot_config::apply(boot, &boot_config::edits(&config, &[])?)?;
+        boot.write(
+            cloud_init::USER_DATA,
+            user_data.render().as_bytes(),
+            Mode::FILE,
+        )?;
+        boot.write(
+            cloud_init::NETWORK_CONFIG,
+            network_config.as_bytes(),
+            Mode::FILE,
+        )?;
+        if let Some(script) = &settings.first_boot {
+            first_boot::write_script(boot, script)?;
+        }
         Ok(())
     })?;
     println!("Done");
@@ -171,20 +730,415 @@ pub(crate) fn main(Args { name }: Args) -> Result<()> {
     Ok(())
 }
 
-fn with(
-    partition: impl AsRef<Path>,
-    f: impl FnOnce(&Path) -> Result<()>,
+/// Enable ssh, wifi and the user on the boot partition, and apply the boot
+/// settings
+fn write_boot(
+    boot: &mut dyn Volume,
+    settings: &Settings,
+    network_manager: bool,
 ) -> Result<()> {
-    let tempdir = tempfile::tempdir()?;
-    mount(partition, tempdir.path())?;
-    let _umount = defer::defer(|| umount(tempdir.path()));
-    f(tempdir.path())
+    boot.write("ssh", b"", Mode::FILE)?;
+
+    let config = if network_manager {
+        // NetworkManager doesn't set the regulatory domain itself
+        boot_config(settings, Some(&settings.country))?
+    } else {
+        let wifi = wifi::wpa_supplicant(&settings.country, &settings.networks);
+        boot.write("wpa_supplicant.conf", wifi.as_bytes(), Mode::FILE)?;
+        boot_config(settings, None)?
+    };
+    boot_config::apply(boot, &boot_config::edits(&config, &[])?)?;
+
+    // Picked up on first boot to create the user (or rename pi)
+    let userconf = format!("{}:{}", settings.user, settings.password_hash);
+    boot.write("userconf", userconf.as_bytes(), Mode::FILE)
+}
+
+/// The boot settings the customization needs followed by those asked for,
+/// setting the wifi regulatory domain on the kernel command line if given
+fn boot_config(
+    settings: &Settings,
+    regulatory_domain: Option<&str>,
+) -> Result<Vec<Setting>> {
+    let mut config = Vec::new();
+    if !settings.static_ip.as_ref().is_some_and(StaticIp::has_ipv6) {
+        config.push(Setting::cmdline("ipv6.disable", Some("1"))?);
+    }
+    if let Some(country) = regulatory_domain {
+        config.push(Setting::cmdline(
+            "cfg80211.ieee80211_regdom",
+            Some(country),
+        )?);
+    }
+    config.extend(settings.boot_config.iter().cloned());
+    Ok(config)
+}
+
+/// Authorize the pi's identity for the user and give sshd fixed host keys,
+/// so `pi register` can trust the pi without a password
+fn install_keys(
+    root: &mut dyn Volume,
+    custom: &Customization<'_>,
+) -> Result<()> {
+    // userconf renames the first user and moves its home, so the key has to
+    // go in the home it has now
+    let passwd = root.read_to_string("etc/passwd")?.unwrap_or_default();
+    let (home, uid, gid) = first_user(&passwd).unwrap_or_else(|| {
+        (
+            format!("home/{}", custom.settings.user),
+            FIRST_UID,
+            FIRST_UID,
+        )
+    });
+    if let Some((parent, _)) = home.rsplit_once('/') {
+        root.create_dir(parent, Mode::DIR)?;
+    }
+    root.create_dir(&home, Mode::owned(0o755, uid, gid))?;
+    let ssh = format!("{home}/.ssh");
+    root.create_dir(&ssh, Mode::owned(0o700, uid, gid))?;
+    root.write(
+        &format!("{ssh}/authorized_keys"),
+        format!("{}\n", custom.authorized_key).as_bytes(),
+        Mode::owned(0o600, uid, gid),
+    )?;
+
+    root.create_dir("etc/ssh", Mode::DIR)?;
+    for key in &custom.host_keys {
+        let file = format!("etc/ssh/{}", key.file());
+        root.write(&file, &key.private, Mode::root(0o600))?;
+        root.write(&format!("{file}.pub"), key.public.as_bytes(), Mode::FILE)?;
+    }
+    let _ = systemd::disable(root, REGENERATE_HOST_KEYS)?;
+
+    Ok(())
+}
+
+/// The home directory (relative to the root), uid and gid of the first user
+/// in `passwd`
+fn first_user(passwd: &str) -> Option<(String, u32, u32)> {
+    passwd.lines().find_map(|line| {
+        let fields = line.split(':').collect::<Vec<_>>();
+        let uid = fields.get(2)?.parse().ok()?;
+        if uid != FIRST_UID {
+            return None;
+        }
+        let gid = fields.get(3)?.parse().ok()?;
+        let home = fields.get(5)?.trim_start_matches('/');
+        (!home.is_empty()).then(|| (home.to_owned(), uid, gid))
+    })
+}
+
+/// The public half of `name`'s identity, generating one if it has none (or
+/// standing in for it on a dry run)
+fn authorized_key(name: &str, dry_run: bool) -> Result<String> {
+    let id = match Identity::new_unknown(name)?.exists() {
+        Ok(id) => id,
+        Err(_) if dry_run => {
+            return Ok(format!("<the identity pi image generates for {name}>"));
+        }
+        Err(id) => id.generate(name)?,
+    };
+    Ok(fs::read_to_string(&id.public)?.trim().to_owned())
+}
+
+/// The contents of the first boot script, if there is one
+fn read_script(path: Option<PathBuf>) -> Result<Option<Vec<u8>>> {
+    path.map(|path| {
+        fs::read(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+    })
+    .transpose()
+}
+
+/// Static addressing from the command line, or the profile if none was given
+fn static_ip(
+    interface: Option<String>,
+    addresses: &[Address],
+    gateways: &[IpAddr],
+    dns: Vec<IpAddr>,
+    profile: profile::Network,
+) -> Result<Option<StaticIp>> {
+    let interface = interface
+        .or(profile.interface)
+        .unwrap_or_else(|| String::from(static_ip::DEFAULT_INTERFACE));
+    if addresses.is_empty() && gateways.is_empty() && dns.is_empty() {
+        StaticIp::new(
+            interface,
+            &profile.address,
+            &profile.gateway,
+            profile.dns,
+        )
+    } else {
+        StaticIp::new(interface, addresses, gateways, dns)
+    }
+}
+
+/// The hashed password for `user`, from the command line, then the profile,
+/// then prompting (or a placeholder on a dry run)
+fn password_hash(
+    user: &str,
+    password_file: Option<PathBuf>,
+    profile: &profile::User,
+    dry_run: bool,
+) -> Result<String> {
+    if password_file.is_none()
+        && let Some(hash) = &profile.password_hash
+    {
+        crypt::validate(hash)?;
+        return Ok(hash.clone());
+    }
+    let password_file = password_file.or_else(|| profile.password_file.clone());
+    let password = if let Some(file) = password_file {
+        profile::read_secret(&file)?
+    } else if dry_run {
+        return Ok(format!(
+            "<the hash of the password pi image asks {user} for>"
+        ));
+    } else {
+        let question = format!("Password for {user}");
+        let hint = "--password-file or a profile";
+        let password = utils::ask_secret(None, &question, hint)?;
+        let again = utils::ask_secret(None, "Confirm password", hint)?;
+        if password != again {
+            bail!("Passwords don't match")
+        }
+        password
+    };
+    if password.is_empty() {
+        bail!("The password for {user} can't be empty")
+    }
+    crypt::sha512(&password)
+}
+
+/// An explicitly requested image file or cached image, or the default image
+/// for this build
+fn source(image: Option<&str>) -> Result<Payload> {
+    match image {
+        Some(image) if Path::new(image).is_file() => Payload::from_file(image),
+        Some(image) => images::open(image),
+        None => raspbian(),
+    }
+}
+
+fn info(image: &Payload) -> Result<()> {
+    if let Some(manifest) = &image.manifest {
+        println!("{manifest}");
+    } else {
+        println!("Image without a manifest");
+        println!("Size:         {}", utils::format_size(image.length));
+    }
+    println!("Compression:  {}", image.compression()?);
+    Ok(())
+}
+
+pub(crate) fn verify_checksum(image: &Payload) -> Result<()> {
+    if image.manifest.is_some() {
+        prompt!("Verifying image checksum...");
+        image.verify()?;
+        println!("Done");
+    }
+    Ok(())
+}
+
+/// The size of the image and how it's compressed
+pub(crate) fn describe(image: &Payload) -> Result<String> {
+    let compression = match image.compression()? {
+        Compression::None => String::new(),
+        compression => format!(", {compression} compressed"),
+    };
+    Ok(format!("{}{compression}", utils::format_size(image.length)))
+}
+
+/// Flash `image` to `target`, reading it back afterwards if asked to
+fn write(
+    image: &Payload,
+    target: &Target,
+    verify: bool,
+    show_progress: bool,
+) -> Result<()> {
+    let written = flash::write(image, &mut target.create()?, show_progress)?;
+    if verify {
+        if show_progress {
+            println!("Verifying {}...", target.path().display());
+        }
+        flash::verify(image, target.path(), &written, show_progress)?;
+    }
+    if let Target::Device(device) = target {
+        device.reread_partitions()?;
+    }
+    Ok(())
+}
+
+/// Where the image is written and customized
+enum Target {
+    Device(Device),
+    File(PathBuf),
+    /// An image file that's only read, with what would be changed in it
+    /// recorded
+    DryRun(DryRun),
+}
+
+impl Target {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Device(device) => &device.path,
+            Self::File(path) | Self::DryRun(DryRun { path, .. }) => path,
+        }
+    }
+
+    fn exists(&self) -> bool {
+        match self {
+            Self::Device(_) => true,
+            Self::File(path) | Self::DryRun(DryRun { path, .. }) => {
+                path.exists()
+            }
+        }
+    }
+
+    fn create(&self) -> Result<File> {
+        Ok(match self {
+            Self::Device(device) => {
+                OpenOptions::new().write(true).open(&device.path)?
+            }
+            Self::File(path) => File::create(path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?,
+            Self::DryRun(_) => bail!("A dry run doesn't write the image"),
+        })
+    }
+
+    /// Give `f` access to the filesystem on partition `number`
+    ///
+    /// Devices are mounted in a temporary directory, image files are edited
+    /// in place (using the offset from the image's partition table) so they
+    /// don't need root
+    fn with_partition<T>(
+        &self,
+        number: u32,
+        f: impl FnOnce(&mut dyn Volume) -> Result<T>,
+    ) -> Result<T> {
+        match self {
+            Self::Device(device) => {
+                let tempdir = tempfile::tempdir()?;
+                mount(device.partition(number), tempdir.path())?;
+                let _umount = defer::defer(|| umount(tempdir.path()));
+                f(&mut Mounted(tempdir.path().to_owned()))
+            }
+            Self::File(path) => {
+                with_volume(path, mbr::partition(path, number)?, true, f)
+            }
+            Self::DryRun(dry_run) => dry_run.with_partition(number, f),
+        }
+    }
+}
+
+/// An image being customized without being written to
+///
+/// The partitions are opened read only, what's written to them is kept in
+/// memory (and recorded in the plan) and read back from there
+#[derive(Debug)]
+struct DryRun {
+    path: PathBuf,
+    /// Where the image starts in the file
+    offset: u64,
+    changes: RefCell<BTreeMap<u32, Changes>>,
+    plan: RefCell<Plan>,
+}
+
+impl DryRun {
+    fn new(path: PathBuf, offset: u64) -> Self {
+        Self {
+            path,
+            offset,
+            changes: RefCell::default(),
+            plan: RefCell::default(),
+        }
+    }
+
+    fn with_partition<T>(
+        &self,
+        number: u32,
+        f: impl FnOnce(&mut dyn Volume) -> Result<T>,
+    ) -> Result<T> {
+        let mut file = File::open(&self.path).with_context(|| {
+            format!("Failed to open {}", self.path.display())
+        })?;
+        let _ = file.seek(SeekFrom::Start(self.offset))?;
+        let mut sector = [0; 512];
+        file.read_exact(&mut sector)?;
+        let partition = mbr::parse(&sector)
+            .with_context(|| {
+                format!("Bad partition table in {}", self.path.display())
+            })?
+            .into_iter()
+            .find(|partition| partition.number == number)
+            .ok_or_else(|| {
+                anyhow!("{} has no partition {number}", self.path.display())
+            })?;
+        let partition = mbr::Partition {
+            start: self.offset + partition.start,
+            ..partition
+        };
+        with_volume(&self.path, partition, false, |base| {
+            let mut changes = self.changes.borrow_mut();
+            let mut volume = CopyOnWrite {
+                base,
+                changes: changes.entry(number).or_default(),
+            };
+            f(&mut self.plan.borrow_mut().record(number, &mut volume))
+        })
+    }
+}
+
+/// Give `f` access to the filesystem on `partition` of the image file at
+/// `path`, which is only read unless `write` is set
+fn with_volume<T>(
+    path: &Path,
+    partition: mbr::Partition,
+    write: bool,
+    f: impl FnOnce(&mut dyn Volume) -> Result<T>,
+) -> Result<T> {
+    match partition.kind {
+        mbr::FAT12
+        | mbr::FAT16
+        | mbr::FAT32_CHS
+        | mbr::FAT32_LBA
+        | mbr::FAT16_LBA => {
+            let mut fat = if write {
+                Fat::open(path, partition)?
+            } else {
+                Fat::open_read_only(path, partition)?
+            };
+            let result = f(&mut fat)?;
+            fat.close()?;
+            Ok(result)
+        }
+        // debugfs only opens the image for writing when it's asked to write
+        mbr::LINUX => f(&mut Ext::open(path, partition)?),
+        kind => bail!(
+            "Partition {} of {} has an unsupported type ({kind:#04x})",
+            partition.number,
+            path.display()
+        ),
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Device(device) => write!(f, "the SDCard in {device}"),
+            Self::File(path) | Self::DryRun(DryRun { path, .. }) => {
+                write!(f, "{}", path.display())
+            }
+        }
+    }
 }
 
 fn mount(src: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
-    let src = src.as_ref();
-    let target = target.as_ref();
-    Command::new("mount").args([src, target]).check_status()?;
+    Command::new("mount")
+        .arg(src.as_ref())
+        .arg(target.as_ref())
+        .check_status()?;
     Ok(())
 }
 
@@ -0,0 +1,194 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use argh::FromArgs;
+
+use crate::{
+    payload::{Manifest, Payload},
+    utils,
+};
+
+const IMAGES: &str = "images";
+const IMAGE_EXT: &str = "img";
+const MANIFEST_EXT: &str = "manifest";
+
+/// Manage the local cache of images usable with `pi image --image`
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "images")]
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Add(AddArgs),
+    List(ListArgs),
+    Remove(RemoveArgs),
+}
+
+/// Copy an image into the cache
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "add")]
+struct AddArgs {
+    /// the image file to add
+    #[argh(positional)]
+    image: PathBuf,
+    /// the name to store the image under, defaults to the file name without
+    /// extensions
+    #[argh(option)]
+    name: Option<String>,
+    /// the OS name to record in the manifest, guessed from the image file
+    /// name if not given
+    #[argh(option)]
+    os: Option<String>,
+    /// the OS release date to record in the manifest, guessed from the image
+    /// file name if not given
+    #[argh(option)]
+    release: Option<String>,
+    /// the architecture to record in the manifest, guessed from the image
+    /// file name if not given
+    #[argh(option)]
+    arch: Option<String>,
+}
+
+/// List the cached images
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "list")]
+struct ListArgs {}
+
+/// Remove an image from the cache
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "remove")]
+struct RemoveArgs {
+    /// the cached image to remove
+    #[argh(positional)]
+    name: String,
+}
+
+pub(crate) fn main(Args { command }: Args) -> Result<()> {
+    match command {
+        Command::Add(AddArgs {
+            image,
+            name,
+            os,
+            release,
+            arch,
+        }) => add(&image, name, os, release, arch),
+        Command::List(ListArgs {}) => list(),
+        Command::Remove(RemoveArgs { name }) => remove(&name),
+    }
+}
+
+/// Open a cached image by name
+pub(crate) fn open(name: &str) -> Result<Payload> {
+    let (image, manifest) = paths(name)?;
+    if !image.is_file() {
+        bail!("No cached image named {name} (see pi images list)")
+    }
+    let manifest = Manifest::parse(
+        &fs::read_to_string(&manifest)
+            .with_context(|| format!("Missing manifest for {name}"))?,
+    )
+    .with_context(|| format!("Failed to parse the manifest for {name}"))?;
+    Payload::from_file_with_manifest(image, manifest)
+}
+
+fn add(
+    image: &Path,
+    name: Option<String>,
+    os: Option<String>,
+    release: Option<String>,
+    arch: Option<String>,
+) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => image
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| {
+                anyhow!("Can't derive a name from {}", image.display())
+            })?
+            .to_owned(),
+    };
+    let (cached, manifest_path) = paths(&name)?;
+    if cached.exists() {
+        bail!("There is already a cached image named {name}")
+    }
+
+    let manifest = Manifest::describe(image, os, release, arch)?;
+    prompt!("Copying {} to the cache...", image.display());
+    let _ = fs::copy(image, &cached)?;
+    fs::write(manifest_path, manifest.serialize())?;
+    println!("Done");
+
+    println!("Added {name}");
+    println!("{manifest}");
+    Ok(())
+}
+
+fn list() -> Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir()?)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(IMAGE_EXT)
+            && let Some(name) = path.file_stem().and_then(|name| name.to_str())
+        {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    for name in names {
+        match open(&name).map(|payload| payload.manifest) {
+            Ok(Some(manifest)) => println!(
+                "{name}: {} {} {} ({})",
+                manifest.os,
+                manifest.release,
+                manifest.arch,
+                utils::format_size(manifest.length)
+            ),
+            Ok(None) => println!("{name}"),
+            Err(e) => println!("{name}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+fn remove(name: &str) -> Result<()> {
+    let (image, manifest) = paths(name)?;
+    if !image.exists() {
+        bail!("No cached image named {name}")
+    }
+    fs::remove_file(image)?;
+    match fs::remove_file(manifest) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+fn dir() -> Result<PathBuf> {
+    let path = utils::app_config()?.join(IMAGES);
+    if !path.exists() {
+        fs::create_dir(&path)?;
+    }
+    Ok(path)
+}
+
+fn paths(name: &str) -> Result<(PathBuf, PathBuf)> {
+    if name.is_empty() || name.contains('/') {
+        bail!("Invalid image name {name}")
+    }
+    let dir = dir()?;
+    Ok((
+        dir.join(format!("{name}.{IMAGE_EXT}")),
+        dir.join(format!("{name}.{MANIFEST_EXT}")),
+    ))
+}
@@ -0,0 +1,82 @@
+use std::{collections::BTreeMap, fs, io::ErrorKind, time::SystemTime};
+
+use anyhow::{Context as _, Result, anyhow};
+use argh::FromArgs;
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+const INVENTORY: &str = "inventory.toml";
+
+/// List the pis imaged so far
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "inventory")]
+pub(crate) struct Args {}
+
+/// What was written to a pi's card
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    /// RFC 3339, in UTC
+    imaged: String,
+    image: String,
+    user: String,
+    /// The hostname pattern, for pis imaged in a batch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    batch: Option<String>,
+}
+
+pub(crate) fn main(Args {}: Args) -> Result<()> {
+    let inventory = load()?;
+    if inventory.is_empty() {
+        println!("Nothing has been imaged yet");
+    }
+    for (name, entry) in inventory {
+        print!(
+            "{name}: {}, {} as {}",
+            entry.imaged, entry.image, entry.user
+        );
+        match entry.batch {
+            Some(batch) => println!(" (batch {batch})"),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+/// Record that `name` was just imaged, replacing anything recorded for it
+/// before
+pub(crate) fn record(
+    name: &str,
+    image: &str,
+    user: &str,
+    batch: Option<&str>,
+) -> Result<()> {
+    let mut inventory = load()?;
+    let _ = inventory.insert(
+        name.to_owned(),
+        Entry {
+            imaged: humantime::format_rfc3339_seconds(SystemTime::now())
+                .to_string(),
+            image: image.to_owned(),
+            user: user.to_owned(),
+            batch: batch.map(str::to_owned),
+        },
+    );
+    let path = utils::app_config()?.join(INVENTORY);
+    fs::write(&path, toml::to_string(&inventory)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn load() -> Result<BTreeMap<String, Entry>> {
+    let path = utils::app_config()?.join(INVENTORY);
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => {
+            Err(anyhow!(e)
+                .context(format!("Failed to read {}", path.display())))
+        }
+    }
+}
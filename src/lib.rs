@@ -31,10 +31,32 @@ pub use self::{
 
 #[macro_use]
 mod macros;
+mod batch;
+mod boot_config;
+mod bundle;
+mod card;
 mod cat;
+mod cloud_init;
+mod crypt;
+mod device;
+mod emulate;
+mod first_boot;
+mod flash;
+mod host_keys;
 mod identity;
 mod image;
+mod images;
+mod inventory;
+mod locale;
+mod mbr;
 mod mount;
+mod netboot;
+mod networks;
+mod os_release;
+mod overlay;
+mod payload;
+mod plan;
+mod profile;
 mod pull;
 mod push;
 mod register;
@@ -43,7 +65,12 @@ mod secure;
 mod send;
 mod setup;
 mod ssh;
+mod static_ip;
+mod systemd;
+mod users;
 mod utils;
+mod volume;
+mod wifi;
 
 /// Extension trait for [Command](std::process::Command)
 #[sealed::sealed]
@@ -59,7 +86,13 @@ pub trait CommandExt: Sized {
 impl CommandExt for std::process::Command {
     fn run_on_pi(&mut self, name: &str) -> Result<Self> {
         let ip = resolve(name)?;
-        Ok(self.run_on_remote("pi", ip, Identity::private(name)?))
+        Ok(
+            self.run_on_remote(
+                &users::get(name)?,
+                ip,
+                Identity::private(name)?,
+            ),
+        )
     }
 }
 
@@ -76,10 +109,20 @@ pub struct Args {
     command: Command,
 }
 
+// Parsed once, so the size of image's options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, FromArgs)]
 #[argh(subcommand)]
 enum Command {
     Image(image::Args),
+    BootConfig(boot_config::Args),
+    Bundle(bundle::Args),
+    Card(card::Args),
+    Emulate(emulate::Args),
+    Images(images::Args),
+    Inventory(inventory::Args),
+    Netboot(netboot::Args),
+    Networks(networks::Args),
     Resolve(resolve::Args),
     Ssh(ssh::Args),
     Register(register::Args),
@@ -97,6 +140,14 @@ enum Command {
 pub fn main(Args { command }: Args) -> Result<ExitCode> {
     match command {
         Command::Image(args) => image::main(args)?,
+        Command::BootConfig(args) => boot_config::main(args)?,
+        Command::Bundle(args) => bundle::main(args)?,
+        Command::Card(args) => card::main(args)?,
+        Command::Emulate(args) => emulate::main(args)?,
+        Command::Images(args) => images::main(args)?,
+        Command::Inventory(args) => inventory::main(args)?,
+        Command::Netboot(args) => netboot::main(args)?,
+        Command::Networks(args) => networks::main(args)?,
         Command::Resolve(args) => resolve::main(args)?,
         Command::Register(args) => register::main(args)?,
         Command::Mount(args) => mount::main(args)?,
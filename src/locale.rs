@@ -0,0 +1,274 @@
+use std::fmt::Write as _;
+
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::{
+    systemd,
+    volume::{Mode, Volume},
+};
+
+const ZONEINFO: &str = "usr/share/zoneinfo";
+const LOCALE_GEN: &str = "etc/locale.gen";
+const KEYBOARD: &str = "etc/default/keyboard";
+/// Locales glibc always has, which locale-gen doesn't make (or list)
+const BUILT_IN: [&str; 4] = ["C", "C.UTF-8", "C.utf8", "POSIX"];
+// locale-gen has to run on the pi, so a unit does it on first boot and then
+// disables itself
+const LOCALE_GEN_UNIT: &str = "pi-locale-gen.service";
+const LOCALE_GEN_SERVICE: &str = "\
+[Unit]
+Description=Generate the locale chosen when the image was written
+Before=systemd-user-sessions.service
+
+[Service]
+Type=oneshot
+ExecStart=/usr/sbin/locale-gen
+ExecStartPost=/bin/systemctl disable pi-locale-gen.service
+
+[Install]
+WantedBy=multi-user.target
+";
+// Debian's defaults, for images without a keyboard configuration
+const DEFAULT_KEYBOARD: &str = "\
+XKBMODEL=\"pc105\"
+XKBLAYOUT=\"gb\"
+XKBVARIANT=\"\"
+XKBOPTIONS=\"\"
+
+BACKSPACE=\"guess\"
+";
+
+/// Timezone, locale and keyboard layout for a new image, each left as the
+/// image has it if not given
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Locale {
+    /// A tz database name, as in Europe/London
+    pub(crate) timezone: Option<String>,
+    /// As in en_GB.UTF-8
+    pub(crate) lang: Option<String>,
+    /// An X keyboard layout, as in gb
+    pub(crate) keyboard: Option<String>,
+}
+
+impl Locale {
+    /// Check everything given is well formed, whether the image knows it is
+    /// only found out once it's written
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(timezone) = &self.timezone {
+            let valid = !timezone.is_empty()
+                && timezone.split('/').all(|part| {
+                    !part.is_empty()
+                        && !part.starts_with('.')
+                        && part.chars().all(|c| {
+                            c.is_ascii_alphanumeric() || "_+-".contains(c)
+                        })
+                });
+            if !valid {
+                bail!("Invalid timezone {timezone} (as in Europe/London)")
+            }
+        }
+        if let Some(lang) = &self.lang {
+            let valid = BUILT_IN.contains(&lang.as_str())
+                || lang.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+                    && lang.chars().all(|c| {
+                        c.is_ascii_alphanumeric() || "_.@-".contains(c)
+                    });
+            if !valid {
+                bail!("Invalid locale {lang} (as in en_GB.UTF-8 or C.UTF-8)")
+            }
+        }
+        if let Some(keyboard) = &self.keyboard {
+            let valid = !keyboard.is_empty()
+                && keyboard.chars().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'
+                });
+            if !valid {
+                bail!("Invalid keyboard layout {keyboard} (as in gb)")
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Write the settings into the root partition
+    pub(crate) fn apply(&self, root: &mut dyn Volume) -> Result<()> {
+        if let Some(timezone) = &self.timezone {
+            let zoneinfo = format!("{ZONEINFO}/{timezone}");
+            if root.read(&zoneinfo)?.is_none() {
+                bail!("The image doesn't know the timezone {timezone}")
+            }
+            root.write(
+                "etc/timezone",
+                format!("{timezone}\n").as_bytes(),
+                Mode::FILE,
+            )?;
+            root.symlink("etc/localtime", &format!("/{zoneinfo}"))?;
+        }
+
+        if let Some(lang) = &self.lang
+            && !BUILT_IN.contains(&lang.as_str())
+        {
+            let locale_gen = root
+                .read_to_string(LOCALE_GEN)?
+                .ok_or_else(|| anyhow!("The image has no /{LOCALE_GEN}"))?;
+            let locale_gen =
+                enable_locale(&locale_gen, lang).ok_or_else(|| {
+                    anyhow!("The image doesn't support the locale {lang}")
+                })?;
+            root.write(LOCALE_GEN, locale_gen.as_bytes(), Mode::FILE)?;
+            systemd::enable(root, LOCALE_GEN_UNIT, LOCALE_GEN_SERVICE)?;
+        }
+        if let Some(lang) = &self.lang {
+            root.write(
+                "etc/default/locale",
+                format!("LANG={lang}\n").as_bytes(),
+                Mode::FILE,
+            )?;
+        }
+
+        if let Some(keyboard) = &self.keyboard {
+            let current = root
+                .read_to_string(KEYBOARD)?
+                .unwrap_or_else(|| String::from(DEFAULT_KEYBOARD));
+            root.write(
+                KEYBOARD,
+                set_layout(&current, keyboard).as_bytes(),
+                Mode::FILE,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `locale_gen` with `lang` uncommented, None if it isn't listed at all
+fn enable_locale(locale_gen: &str, lang: &str) -> Option<String> {
+    let mut found = false;
+    let mut result = String::new();
+    for line in locale_gen.lines() {
+        let entry = line.trim_start_matches(['#', ' ']);
+        if entry.split_whitespace().next() == Some(lang)
+            && entry.split_whitespace().count() == 2
+        {
+            found = true;
+            let _ = writeln!(result, "{entry}");
+        } else {
+            let _ = writeln!(result, "{line}");
+        }
+    }
+    found.then_some(result)
+}
+
+/// /etc/default/keyboard with the layout replaced and any variant for the old
+/// layout cleared
+fn set_layout(keyboard: &str, layout: &str) -> String {
+    let mut result = String::new();
+    let mut found = false;
+    for line in keyboard.lines() {
+        if line.starts_with("XKBLAYOUT=") {
+            found = true;
+            let _ = writeln!(result, "XKBLAYOUT=\"{layout}\"");
+        } else if line.starts_with("XKBVARIANT=") {
+            let _ = writeln!(result, "XKBVARIANT=\"\"");
+        } else {
+            let _ = writeln!(result, "{line}");
+        }
+    }
+    if !found {
+        let _ = writeln!(result, "XKBLAYOUT=\"{layout}\"");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::volume::Mounted;
+
+    const LOCALE_GEN_CONTENTS: &str = "\
+# en_GB ISO-8859-1
+# en_GB.UTF-8 UTF-8
+# fr_FR.UTF-8 UTF-8
+";
+
+    fn lang(lang: &str) -> Locale {
+        Locale {
+            lang: Some(lang.to_owned()),
+            ..Locale::default()
+        }
+    }
+
+    fn root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc/default")).unwrap();
+        fs::write(root.path().join(LOCALE_GEN), LOCALE_GEN_CONTENTS).unwrap();
+        root
+    }
+
+    fn read(root: &Path, path: &str) -> Option<String> {
+        fs::read_to_string(root.join(path)).ok()
+    }
+
+    #[test]
+    fn validate_langs() {
+        for valid in ["en_GB.UTF-8", "de_DE@euro", "C", "C.UTF-8", "POSIX"] {
+            lang(valid).validate().unwrap();
+        }
+        for invalid in ["", "EN_gb", "en GB", "C.ISO-8859-1", "../x"] {
+            assert!(lang(invalid).validate().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn generated_locale() {
+        let root = root();
+        lang("en_GB.UTF-8")
+            .apply(&mut Mounted(root.path().to_owned()))
+            .unwrap();
+        assert_eq!(
+            read(root.path(), LOCALE_GEN).unwrap(),
+            "# en_GB ISO-8859-1\nen_GB.UTF-8 UTF-8\n# fr_FR.UTF-8 UTF-8\n"
+        );
+        assert_eq!(
+            read(root.path(), "etc/default/locale").unwrap(),
+            "LANG=en_GB.UTF-8\n"
+        );
+        assert!(
+            root.path()
+                .join("etc/systemd/system")
+                .join(LOCALE_GEN_UNIT)
+                .exists()
+        );
+    }
+
+    #[test]
+    fn built_in_locale_isnt_generated() {
+        let root = root();
+        lang("C.UTF-8")
+            .apply(&mut Mounted(root.path().to_owned()))
+            .unwrap();
+        assert_eq!(read(root.path(), LOCALE_GEN).unwrap(), LOCALE_GEN_CONTENTS);
+        assert_eq!(
+            read(root.path(), "etc/default/locale").unwrap(),
+            "LANG=C.UTF-8\n"
+        );
+        assert!(!root.path().join("etc/systemd").exists());
+    }
+
+    #[test]
+    fn unknown_locale() {
+        let root = root();
+        assert!(
+            lang("xx_XX.UTF-8")
+                .apply(&mut Mounted(root.path().to_owned()))
+                .is_err()
+        );
+    }
+}
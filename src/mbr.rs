@@ -0,0 +1,188 @@
+use std::{
+    fs::File,
+    io::{Read as _, Seek as _, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+
+const SECTOR_SIZE: u64 = 512;
+// Partition types
+pub(crate) const FAT12: u8 = 0x01;
+pub(crate) const FAT16: u8 = 0x06;
+pub(crate) const FAT32_CHS: u8 = 0x0b;
+pub(crate) const FAT32_LBA: u8 = 0x0c;
+pub(crate) const FAT16_LBA: u8 = 0x0e;
+pub(crate) const LINUX: u8 = 0x83;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const PROTECTIVE_GPT: u8 = 0xee;
+
+/// A primary partition from an MBR partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Partition {
+    /// 1-based, matching the kernel's numbering
+    pub(crate) number: u32,
+    pub(crate) kind: u8,
+    /// Byte offset from the start of the disk
+    pub(crate) start: u64,
+    /// Length in bytes
+    pub(crate) size: u64,
+}
+
+/// The first sector of `path`, which holds the MBR
+pub(crate) fn sector(path: &Path) -> Result<[u8; 512]> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let _ = file.seek(SeekFrom::Start(0))?;
+    let mut sector = [0; 512];
+    file.read_exact(&mut sector).with_context(|| {
+        format!("{} is too small to have a partition table", path.display())
+    })?;
+    Ok(sector)
+}
+
+/// Read the primary partitions from the MBR at the start of `path`
+pub(crate) fn read(path: &Path) -> Result<Vec<Partition>> {
+    parse(&sector(path)?)
+        .with_context(|| format!("Bad partition table in {}", path.display()))
+}
+
+/// Find partition `number` in the MBR at the start of `path`
+pub(crate) fn partition(path: &Path, number: u32) -> Result<Partition> {
+    read(path)?
+        .into_iter()
+        .find(|partition| partition.number == number)
+        .ok_or_else(|| anyhow!("{} has no partition {number}", path.display()))
+}
+
+/// Change the length of partition `number` in `sector`, an MBR, to `size`
+/// bytes (rounded up to whole sectors)
+///
+/// The CHS addresses are left alone, nothing reads them any more
+pub(crate) fn resize(
+    sector: &mut [u8; 512],
+    number: u32,
+    size: u64,
+) -> Result<()> {
+    let index = usize::try_from(number)?
+        .checked_sub(1)
+        .filter(|index| *index < 4)
+        .ok_or_else(|| anyhow!("There's no primary partition {number}"))?;
+    let sectors = u32::try_from(size.div_ceil(SECTOR_SIZE)).map_err(|_| {
+        anyhow!("An MBR can't hold a partition of {size} bytes")
+    })?;
+    let offset = TABLE_OFFSET + index * ENTRY_SIZE + 12;
+    sector[offset..offset + 4].copy_from_slice(&sectors.to_le_bytes());
+    Ok(())
+}
+
+/// The primary partitions in `sector`, an MBR
+pub(crate) fn parse(sector: &[u8; 512]) -> Result<Vec<Partition>> {
+    if sector[510..] != SIGNATURE {
+        bail!("Missing MBR boot signature")
+    }
+    let mut partitions = Vec::new();
+    for (index, entry) in sector[TABLE_OFFSET..510]
+        .chunks_exact(ENTRY_SIZE)
+        .enumerate()
+    {
+        let kind = entry[4];
+        if kind == 0 {
+            continue;
+        }
+        if kind == PROTECTIVE_GPT {
+            bail!("GPT partition tables aren't supported")
+        }
+        let start = u32::from_le_bytes(entry[8..12].try_into()?);
+        let sectors = u32::from_le_bytes(entry[12..16].try_into()?);
+        partitions.push(Partition {
+            number: u32::try_from(index)? + 1,
+            kind,
+            start: u64::from(start) * SECTOR_SIZE,
+            size: u64::from(sectors) * SECTOR_SIZE,
+        });
+    }
+    Ok(partitions)
+}
+
+/// An MBR holding `partitions`, as (type, start sector, sector count)
+#[cfg(test)]
+pub(crate) fn table(partitions: &[(u8, u32, u32)]) -> [u8; 512] {
+    let mut sector = [0; 512];
+    for (index, &(kind, start, sectors)) in partitions.iter().enumerate() {
+        let entry = &mut sector[TABLE_OFFSET + index * ENTRY_SIZE..];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+    sector[510..].copy_from_slice(&SIGNATURE);
+    sector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_primary_partitions() {
+        let sector = table(&[(FAT32_LBA, 8192, 1024), (LINUX, 9216, 4096)]);
+        assert_eq!(
+            parse(&sector).unwrap(),
+            [
+                Partition {
+                    number: 1,
+                    kind: FAT32_LBA,
+                    start: 8192 * 512,
+                    size: 1024 * 512,
+                },
+                Partition {
+                    number: 2,
+                    kind: LINUX,
+                    start: 9216 * 512,
+                    size: 4096 * 512,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_skips_empty_entries() {
+        let sector = table(&[(0, 0, 0), (LINUX, 2048, 2048)]);
+        let partitions = parse(&sector).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].number, 2);
+    }
+
+    #[test]
+    fn parse_rejects_missing_signature() {
+        let mut sector = table(&[(LINUX, 2048, 2048)]);
+        sector[511] = 0;
+        assert!(parse(&sector).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_gpt() {
+        let sector = table(&[(PROTECTIVE_GPT, 1, u32::MAX)]);
+        assert!(parse(&sector).is_err());
+    }
+
+    #[test]
+    fn resize_rounds_up_to_sectors() {
+        let mut sector = table(&[(FAT32_LBA, 8192, 1024), (LINUX, 9216, 4096)]);
+        resize(&mut sector, 2, 1000 * 512 + 1).unwrap();
+        let partitions = parse(&sector).unwrap();
+        assert_eq!(partitions[0].size, 1024 * 512);
+        assert_eq!(partitions[1].start, 9216 * 512);
+        assert_eq!(partitions[1].size, 1001 * 512);
+    }
+
+    #[test]
+    fn resize_rejects_bad_partitions_and_sizes() {
+        let mut sector = table(&[(LINUX, 2048, 2048)]);
+        assert!(resize(&mut sector, 0, 512).is_err());
+        assert!(resize(&mut sector, 5, 512).is_err());
+        assert!(resize(&mut sector, 1, u64::from(u32::MAX) * 1024).is_err());
+    }
+}
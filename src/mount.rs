@@ -8,7 +8,7 @@ use anyhow::Result;
 use argh::FromArgs;
 use command_ext::CommandExt;
 
-use crate::{identity::Identity, resolve};
+use crate::{identity::Identity, resolve, users};
 
 /// Mount a directory from a pi locally
 #[derive(Debug, FromArgs)]
@@ -46,7 +46,8 @@ pub fn mount(
 ) -> Result<()> {
     let name = name.as_ref();
     let ip = resolve(name)?;
-    let mut full_src = OsString::from(format!("pi@{ip}:"));
+    let user = users::get(name)?;
+    let mut full_src = OsString::from(format!("{user}@{ip}:"));
     full_src.push(src.as_ref());
     Command::new("sshfs")
         .arg("-o")
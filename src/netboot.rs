@@ -0,0 +1,399 @@
+use std::{
+    fmt,
+    fs::{self, Permissions},
+    net::Ipv4Addr,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context as _, Error, Result, anyhow, bail};
+use argh::FromArgs;
+use nix::unistd::Uid;
+use tempfile::TempDir;
+
+use crate::{
+    batch,
+    boot_config::{self, Edit, Setting},
+    cloud_init,
+    image::{self, BOOT_PARTITION, ROOT_PARTITION},
+    mbr,
+    os_release::OsRelease,
+    volume::{Ext, Fat, Mode, Mounted, Volume},
+};
+
+const SERIAL_DIGITS: usize = 8;
+const CMDLINE_TXT: &str = "cmdline.txt";
+const FSTAB: &str = "etc/fstab";
+
+/// Serve pis over the network instead of from SDCards
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "netboot")]
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Prepare(PrepareArgs),
+}
+
+/// Customize an image for a pi and unpack it into a TFTP directory (the
+/// boot partition) and an NFS export (the root partition) on this machine
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "prepare")]
+struct PrepareArgs {
+    /// the hostname for the pi
+    #[argh(positional)]
+    name: String,
+    /// the pi's serial number, the last 8 hex digits of Serial in
+    /// /proc/cpuinfo, which the firmware looks for in the TFTP directory
+    #[argh(option)]
+    serial: Serial,
+    /// the address of this machine, which the pi mounts its root from
+    #[argh(option)]
+    server: Ipv4Addr,
+    /// the directory served over TFTP, the boot files go in a subdirectory
+    /// named after the serial
+    #[argh(option, default = "PathBuf::from(\"/srv/tftp\")")]
+    tftp: PathBuf,
+    /// the directory exported over NFS, the root goes in a subdirectory named
+    /// after the pi
+    #[argh(option, default = "PathBuf::from(\"/srv/nfs\")")]
+    nfs: PathBuf,
+    /// the image to use, either a path to an image file or the name of a
+    /// cached image (see pi images), defaults to the embedded image
+    #[argh(option)]
+    image: Option<String>,
+    /// the user to create, defaults to pi
+    #[argh(option)]
+    user: Option<String>,
+    /// a file containing the user's password, prompted for if not given
+    #[argh(option)]
+    password_file: Option<PathBuf>,
+    /// a TOML file describing the customization as for pi image (wifi is
+    /// left out, network booted pis are on ethernet)
+    #[argh(option)]
+    profile: Option<PathBuf>,
+    /// replace existing directories for the pi without asking
+    #[argh(switch, short = 'y')]
+    yes: bool,
+}
+
+/// The serial number the firmware requests its boot files under
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Serial(String);
+
+impl FromStr for Serial {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() < SERIAL_DIGITS || !s.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            bail!("Invalid serial number {s} (expected 8 hex digits)")
+        }
+        // /proc/cpuinfo shows 16 digits, but only the last 8 are used
+        Ok(Self(s[s.len() - SERIAL_DIGITS..].to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub(crate) fn main(Args { command }: Args) -> Result<()> {
+    match command {
+        Command::Prepare(args) => prepare(args),
+    }
+}
+
+fn prepare(
+    PrepareArgs {
+        name,
+        serial,
+        server,
+        tftp,
+        nfs,
+        image,
+        user,
+        password_file,
+        profile,
+        yes,
+    }: PrepareArgs,
+) -> Result<()> {
+    // Without root the root filesystem would be unpacked owned by us
+    if !Uid::effective().is_root() {
+        bail!("Preparing a network boot requires root permissions")
+    }
+    if batch::expand(&name)?.is_some() {
+        bail!("Network boot is prepared for one pi at a time")
+    }
+    batch::validate_hostname(&name)?;
+    for dir in [&tftp, &nfs] {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // The pi is told where its root is, so it has to be absolute
+    let nfs = fs::canonicalize(&nfs)?;
+    let boot_dir = tftp.join(serial.to_string());
+    let root_dir = nfs.join(&name);
+    for dir in [&boot_dir, &root_dir] {
+        if dir.exists() && !yes {
+            image::confirm_overwrite(dir.display())?;
+        }
+    }
+
+    // The image is customized as pi image would, then unpacked. It's made
+    // next to the root so it isn't in a (possibly too small) tmpfs
+    let tempdir = tempfile::tempdir_in(&nfs)?;
+    let file = tempdir.path().join(format!("{name}.img"));
+    image::main(image::Args::wired(
+        name.clone(),
+        file.clone(),
+        image,
+        user,
+        password_file,
+        profile,
+    ))?;
+
+    let mut boot = Fat::open(&file, mbr::partition(&file, BOOT_PARTITION)?)?;
+    if boot.read(cloud_init::USER_DATA)?.is_some() {
+        bail!(
+            "The image is configured by cloud-init, which can't find its \
+             configuration on a network booted pi"
+        )
+    }
+
+    // Both are unpacked alongside and only swapped in once they're complete,
+    // so a failure leaves what was being served alone
+    let staged_boot = tempfile::tempdir_in(&tftp)?;
+    let staged_root = tempfile::tempdir_in(&nfs)?;
+    prompt!("Unpacking the boot partition to {}...", boot_dir.display());
+    let copied = boot.extract(staged_boot.path())?;
+    fs::set_permissions(staged_boot.path(), Permissions::from_mode(0o755))?;
+    println!("Done, {copied} files");
+    prompt!("Unpacking the root partition to {}...", root_dir.display());
+    Ext::open(&file, mbr::partition(&file, ROOT_PARTITION)?)?
+        .extract(staged_root.path())?;
+    println!("Done");
+
+    // The pi's services (userconf, ssh, the first boot script, ...) look for
+    // their files where the boot partition would be mounted
+    let mut root = Mounted(staged_root.path().to_owned());
+    let os = OsRelease::read(&mut root)?;
+    let _ = boot.extract(
+        &staged_root
+            .path()
+            .join(os.boot_dir().trim_start_matches('/')),
+    )?;
+
+    let mut served = Mounted(staged_boot.path().to_owned());
+    let cmdline = served
+        .read_to_string(CMDLINE_TXT)?
+        .ok_or_else(|| anyhow!("The boot partition has no {CMDLINE_TXT}"))?;
+    let cmdline = nfs_cmdline(&cmdline, server, &root_dir)?;
+    served.write(CMDLINE_TXT, format!("{cmdline}\n").as_bytes(), Mode::FILE)?;
+    if let Some(fstab) = root.read_to_string(FSTAB)? {
+        let fstab = without_partitions(&fstab, os.boot_dir());
+        root.write(FSTAB, fstab.as_bytes(), Mode::FILE)?;
+    }
+    replace_dir(staged_boot, &boot_dir)?;
+    replace_dir(staged_root, &root_dir)?;
+
+    println!(
+        "{name} will boot from {} and mount {}:{} as its root, once both are \
+         served",
+        boot_dir.display(),
+        server,
+        root_dir.display()
+    );
+    Ok(())
+}
+
+/// Move `staged` to `dir`, removing whatever was there only once it's out of
+/// the way
+fn replace_dir(staged: TempDir, dir: &Path) -> Result<()> {
+    let parent = dir
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent", dir.display()))?;
+    let old = tempfile::tempdir_in(parent)?;
+    let moved = old.path().join("old");
+    let existed = dir.exists();
+    if existed {
+        fs::rename(dir, &moved).with_context(|| {
+            format!("Failed to move {} out of the way", dir.display())
+        })?;
+    }
+    if let Err(e) = fs::rename(staged.path(), dir) {
+        if existed {
+            let _ = fs::rename(&moved, dir);
+        }
+        return Err(anyhow!(e).context(format!(
+            "Failed to move the new files into {}",
+            dir.display()
+        )));
+    }
+    let _ = staged.keep();
+    old.close()
+        .with_context(|| format!("Failed to remove the old {}", dir.display()))
+}
+
+/// `cmdline` changed to mount the root from `root_dir` on `server`
+fn nfs_cmdline(
+    cmdline: &str,
+    server: Ipv4Addr,
+    root_dir: &Path,
+) -> Result<String> {
+    let nfsroot = format!("{server}:{},vers=3,tcp", root_dir.display());
+    let set = [
+        Setting::cmdline("root", Some("/dev/nfs"))?,
+        Setting::cmdline("nfsroot", Some(&nfsroot))?,
+        Setting::cmdline("rw", None)?,
+        Setting::cmdline("ip", Some("dhcp"))?,
+    ];
+    // Checking, resizing and expanding the root partition on first boot
+    // don't apply to NFS
+    let unset = ["rootfstype", "fsck.repair", "init"]
+        .map(|key| Setting::cmdline(key, None))
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    let edits = set
+        .iter()
+        .map(Edit::Set)
+        .chain(unset.iter().map(Edit::Unset))
+        .collect::<Vec<_>>();
+    Ok(boot_config::edit_cmdline(cmdline, &edits))
+}
+
+/// `fstab` with the root and boot partitions (mounted on `boot_dir`) commented
+/// out, a network booted pi has no partitions to mount
+fn without_partitions(fstab: &str, boot_dir: &str) -> String {
+    fstab
+        .lines()
+        .map(|line| match line.split_whitespace().nth(1) {
+            Some(dir)
+                if !line.trim_start().starts_with('#')
+                    && (dir == "/" || dir == boot_dir) =>
+            {
+                format!("#{line}\n")
+            }
+            _ => format!("{line}\n"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
+
+    #[test]
+    fn serial() {
+        assert_eq!("10000000ABCDEF12".parse::<Serial>().unwrap().0, "abcdef12");
+        assert_eq!("0123abcd".parse::<Serial>().unwrap().0, "0123abcd");
+        assert!("abcdef1".parse::<Serial>().is_err());
+        assert!("0123abcg".parse::<Serial>().is_err());
+        assert!("10000000abcdéf1".parse::<Serial>().is_err());
+    }
+
+    #[test]
+    fn cmdline_partuuid() {
+        let cmdline = "console=serial0,115200 console=tty1 \
+                       root=PARTUUID=4e639091-02 rootfstype=ext4 \
+                       fsck.repair=yes rootwait quiet \
+                       init=/usr/lib/raspberrypi-sys-mods/firstboot";
+        assert_eq!(
+            nfs_cmdline(cmdline, SERVER, Path::new("/srv/nfs/pi")).unwrap(),
+            "console=serial0,115200 console=tty1 root=/dev/nfs rootwait quiet \
+             nfsroot=192.168.1.2:/srv/nfs/pi,vers=3,tcp rw ip=dhcp"
+        );
+    }
+
+    #[test]
+    fn cmdline_device() {
+        let cmdline = "dwc_otg.lpm_enable=0 console=tty1 root=/dev/mmcblk0p2 \
+                       rootfstype=ext4 elevator=deadline rootwait\n";
+        let cmdline =
+            nfs_cmdline(cmdline, SERVER, Path::new("/srv/nfs/pi")).unwrap();
+        assert_eq!(
+            cmdline,
+            "dwc_otg.lpm_enable=0 console=tty1 root=/dev/nfs elevator=deadline \
+             rootwait nfsroot=192.168.1.2:/srv/nfs/pi,vers=3,tcp rw ip=dhcp"
+        );
+        // Preparing again changes nothing
+        assert_eq!(
+            nfs_cmdline(&cmdline, SERVER, Path::new("/srv/nfs/pi")).unwrap(),
+            cmdline
+        );
+    }
+
+    #[test]
+    fn fstab_partuuid() {
+        let fstab = "\
+proc            /proc           proc    defaults          0       0
+PARTUUID=4e639091-01  /boot/firmware  vfat    defaults          0       2
+PARTUUID=4e639091-02  /               ext4    defaults,noatime  0       1
+#   a swapfile is not a swap partition, no line here
+";
+        assert_eq!(
+            without_partitions(fstab, "/boot/firmware"),
+            "\
+proc            /proc           proc    defaults          0       0
+#PARTUUID=4e639091-01  /boot/firmware  vfat    defaults          0       2
+#PARTUUID=4e639091-02  /               ext4    defaults,noatime  0       1
+#   a swapfile is not a swap partition, no line here
+"
+        );
+    }
+
+    #[test]
+    fn fstab_device() {
+        let fstab = "\
+proc            /proc           proc    defaults          0       0
+/dev/mmcblk0p1  /boot           vfat    defaults          0       2
+/dev/mmcblk0p2  /               ext4    defaults,noatime  0       1
+/dev/sda1       /srv            ext4    defaults,nofail   0       2
+";
+        assert_eq!(
+            without_partitions(fstab, "/boot"),
+            "\
+proc            /proc           proc    defaults          0       0
+#/dev/mmcblk0p1  /boot           vfat    defaults          0       2
+#/dev/mmcblk0p2  /               ext4    defaults,noatime  0       1
+/dev/sda1       /srv            ext4    defaults,nofail   0       2
+"
+        );
+    }
+
+    #[test]
+    fn fstab_already_commented() {
+        let fstab = "\
+#/dev/mmcblk0p1  /boot           vfat    defaults          0       2
+  # /dev/mmcblk0p2  /               ext4    defaults,noatime  0       1
+";
+        assert_eq!(without_partitions(fstab, "/boot"), fstab);
+    }
+
+    #[test]
+    fn replace_dir_swaps_in_the_new_files() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("pi");
+        for contents in ["first", "second"] {
+            let staged = tempfile::tempdir_in(parent.path()).unwrap();
+            fs::write(staged.path().join(contents), contents).unwrap();
+            replace_dir(staged, &dir).unwrap();
+            let files = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert_eq!(files, [contents]);
+        }
+        // Nothing's left behind next to it
+        assert_eq!(fs::read_dir(parent.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write as _,
+    os::unix::fs::OpenOptionsExt as _,
+    path::PathBuf,
+};
+
+use anyhow::{Context as _, Result, bail};
+use argh::FromArgs;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    profile, utils,
+    wifi::{Key, Network, Security, Ssid},
+};
+
+const NETWORKS: &str = "networks";
+const NETWORK_EXT: &str = "toml";
+
+/// Manage the wifi networks usable with `pi image --network`
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "networks")]
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Add(AddArgs),
+    List(ListArgs),
+    Remove(RemoveArgs),
+}
+
+/// Save a wifi network
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "add")]
+struct AddArgs {
+    /// the name to save the network under
+    #[argh(positional)]
+    name: String,
+    /// the network's SSID, prefixed with hex: to give it in hex
+    #[argh(option)]
+    ssid: String,
+    /// wpa2 (the default), sae (WPA3) or open
+    #[argh(option, default = "Security::Wpa2")]
+    security: Security,
+    /// a file containing the password, prompted for if not given
+    #[argh(option)]
+    psk_file: Option<PathBuf>,
+    /// preference when several saved networks are in range, higher wins
+    #[argh(option, default = "0")]
+    priority: i32,
+    /// the network doesn't broadcast its SSID
+    #[argh(switch)]
+    hidden: bool,
+    /// replace an existing network with the same name
+    #[argh(switch)]
+    force: bool,
+}
+
+/// List the saved networks
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "list")]
+struct ListArgs {}
+
+/// Remove a saved network
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "remove")]
+struct RemoveArgs {
+    /// the network to remove
+    #[argh(positional)]
+    name: String,
+}
+
+/// A network as saved on disk
+///
+/// WPA2 networks are saved with the derived PSK rather than the passphrase
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Saved {
+    ssid: String,
+    #[serde(default)]
+    security: Security,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    psk: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    hidden: bool,
+}
+
+pub(crate) fn main(Args { command }: Args) -> Result<()> {
+    match command {
+        Command::Add(args) => add(args),
+        Command::List(ListArgs {}) => list(),
+        Command::Remove(RemoveArgs { name }) => remove(&name),
+    }
+}
+
+/// Load a saved network by name
+pub(crate) fn open(name: &str) -> Result<Network> {
+    let path = path(name)?;
+    if !path.is_file() {
+        bail!("No saved network named {name} (see pi networks list)")
+    }
+    let saved: Saved = toml::from_str(&fs::read_to_string(&path)?)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let ssid = Ssid::parse(&saved.ssid)?;
+    let key = Key::new(saved.security, &ssid, saved.psk.as_deref())
+        .with_context(|| format!("Bad key for saved network {name}"))?;
+    Ok(Network {
+        ssid,
+        key,
+        priority: saved.priority,
+        hidden: saved.hidden,
+    })
+}
+
+fn add(
+    AddArgs {
+        name,
+        ssid,
+        security,
+        psk_file,
+        priority,
+        hidden,
+        force,
+    }: AddArgs,
+) -> Result<()> {
+    let path = path(&name)?;
+    if path.exists() && !force {
+        bail!("There is already a saved network named {name} (use --force)")
+    }
+
+    let ssid = Ssid::parse(&ssid)?;
+    let secret = match (security, psk_file) {
+        (Security::Open, None) => None,
+        (Security::Open, Some(_)) => {
+            bail!("Open networks have no password, drop --psk-file")
+        }
+        (_, Some(file)) => Some(profile::read_secret(&file)?),
+        (_, None) => Some(utils::ask_secret(None, "Password", "--psk-file")?),
+    };
+    let key = Key::new(security, &ssid, secret.as_deref())?;
+    let saved = Saved {
+        ssid: ssid.to_string(),
+        security: key.security(),
+        psk: key.secret(),
+        priority,
+        hidden,
+    };
+
+    // Saved networks hold secrets
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(toml::to_string(&saved)?.as_bytes())?;
+
+    println!("Saved {name}");
+    Ok(())
+}
+
+fn list() -> Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir()?)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(NETWORK_EXT)
+            && let Some(name) = path.file_stem().and_then(|name| name.to_str())
+        {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    for name in names {
+        match open(&name) {
+            Ok(network) => println!(
+                "{name}: {} ({}{}, priority {})",
+                network.ssid,
+                network.key.security(),
+                if network.hidden { ", hidden" } else { "" },
+                network.priority
+            ),
+            Err(e) => println!("{name}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+fn remove(name: &str) -> Result<()> {
+    let path = path(name)?;
+    if !path.exists() {
+        bail!("No saved network named {name}")
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+fn dir() -> Result<PathBuf> {
+    let path = utils::app_config()?.join(NETWORKS);
+    if !path.exists() {
+        fs::create_dir(&path)?;
+    }
+    Ok(path)
+}
+
+fn path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        bail!("Invalid network name {name}")
+    }
+    Ok(dir()?.join(format!("{name}.{NETWORK_EXT}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_ssid_round_trips() {
+        // An SSID whose text starts with hex: and one that isn't text
+        for ssid in ["hex:6865783a63616665", "hex:ff00"] {
+            let ssid = Ssid::parse(ssid).unwrap();
+            let saved = Saved {
+                ssid: ssid.to_string(),
+                security: Security::Open,
+                psk: None,
+                priority: 0,
+                hidden: false,
+            };
+            let saved: Saved =
+                toml::from_str(&toml::to_string(&saved).unwrap()).unwrap();
+            assert_eq!(Ssid::parse(&saved.ssid).unwrap(), ssid);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Error, Result, bail};
+use serde::Deserialize;
+
+use crate::volume::Volume;
+
+// /etc/os-release is normally a relative symlink to this, which can't be
+// followed from outside the image
+const PATHS: [&str; 2] = ["usr/lib/os-release", "etc/os-release"];
+/// Bookworm, the first Raspberry Pi OS release managed by NetworkManager
+const NETWORK_MANAGER_SINCE: u32 = 12;
+/// Bookworm also moved the boot partition from /boot
+const FIRMWARE_SINCE: u32 = 12;
+
+/// How an image expects to be configured on first boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum OsProfile {
+    /// Raspberry Pi OS, set up with files on the boot partition (userconf,
+    /// ssh, ...) and edits to the root partition
+    Raspbian,
+    /// Ubuntu Server and anything else reading user-data and network-config
+    /// from the boot partition
+    CloudInit,
+}
+
+impl FromStr for OsProfile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raspbian" => Ok(Self::Raspbian),
+            "cloud-init" => Ok(Self::CloudInit),
+            _ => bail!("Unknown OS {s} (expected raspbian or cloud-init)"),
+        }
+    }
+}
+
+impl TryFrom<String> for OsProfile {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for OsProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raspbian => write!(f, "raspbian"),
+            Self::CloudInit => write!(f, "cloud-init"),
+        }
+    }
+}
+
+/// The parts of os-release(5) that decide how an image is customized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OsRelease {
+    pub(crate) id: String,
+    pub(crate) version_id: Option<u32>,
+    pub(crate) codename: Option<String>,
+}
+
+impl OsRelease {
+    /// Read the os-release of the root filesystem on `root`
+    pub(crate) fn read(root: &mut dyn Volume) -> Result<Self> {
+        for path in PATHS {
+            if let Some(contents) = root.read_to_string(path)? {
+                return Ok(Self::parse(&contents));
+            }
+        }
+        bail!("The root partition has no os-release")
+    }
+
+    fn parse(contents: &str) -> Self {
+        let mut release = Self {
+            id: String::from("linux"),
+            version_id: None,
+            codename: None,
+        };
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches(['"', '\'']);
+            match key.trim() {
+                "ID" => value.clone_into(&mut release.id),
+                "VERSION_ID" => release.version_id = value.parse().ok(),
+                "VERSION_CODENAME" => {
+                    release.codename = Some(value.to_owned());
+                }
+                _ => (),
+            }
+        }
+        release
+    }
+
+    /// Whether networking is configured through NetworkManager rather than
+    /// wpa_supplicant.conf on the boot partition
+    pub(crate) fn uses_network_manager(&self) -> bool {
+        self.version_id
+            .is_some_and(|version| version >= NETWORK_MANAGER_SINCE)
+    }
+
+    /// Where the boot partition is mounted on the running pi
+    pub(crate) fn boot_dir(&self) -> &'static str {
+        if self
+            .version_id
+            .is_some_and(|version| version >= FIRMWARE_SINCE)
+        {
+            "/boot/firmware"
+        } else {
+            "/boot"
+        }
+    }
+}
+
+impl fmt::Display for OsRelease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(version) = self.version_id {
+            write!(f, " {version}")?;
+        }
+        if let Some(codename) = &self.codename {
+            write!(f, " ({codename})")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+
+use crate::{
+    utils,
+    volume::{Mode, Volume},
+};
+
+const OVERLAYS: &str = "overlays";
+/// Applied to every pi, before the pi's own overlay
+const COMMON: &str = "common";
+/// Ownership for paths in an overlay, which is otherwise all root's
+///
+/// ```toml
+/// "etc/udev/rules.d/99-serial.rules" = "root:dialout"
+/// "home/pi/.bashrc" = "pi"
+/// "srv/data" = "1000:1000"
+/// ```
+const OWNERS: &str = ".owners.toml";
+
+/// A tree of files copied onto the root partition
+#[derive(Debug)]
+pub(crate) struct Overlay {
+    dir: PathBuf,
+    /// `user[:group]` by path relative to the root
+    owners: HashMap<String, String>,
+}
+
+impl Overlay {
+    /// The common overlay and `name`'s own, those that exist
+    pub(crate) fn find(name: &str) -> Result<Vec<Self>> {
+        let overlays = utils::app_config()?.join(OVERLAYS);
+        [COMMON, name]
+            .into_iter()
+            .map(|dir| overlays.join(dir))
+            .filter(|dir| dir.is_dir())
+            .map(Self::load)
+            .collect()
+    }
+
+    fn load(dir: PathBuf) -> Result<Self> {
+        let path = dir.join(OWNERS);
+        let owners = if path.is_file() {
+            toml::from_str::<HashMap<String, String>>(&fs::read_to_string(
+                &path,
+            )?)
+            .with_context(|| format!("Failed to parse {}", path.display()))?
+            .into_iter()
+            .map(|(path, owner)| (path.trim_matches('/').to_owned(), owner))
+            .collect()
+        } else {
+            HashMap::new()
+        };
+        for path in owners.keys() {
+            if fs::symlink_metadata(dir.join(path)).is_err() {
+                bail!(
+                    "{} gives an owner for {path}, which isn't in the overlay",
+                    dir.join(OWNERS).display()
+                )
+            }
+        }
+        check(&dir)?;
+        Ok(Self { dir, owners })
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Copy the overlay onto `root`, keeping permissions, returning how many
+    /// files were copied
+    ///
+    /// Directories the image already has are left as they are
+    pub(crate) fn apply(&self, root: &mut dyn Volume) -> Result<usize> {
+        let accounts = Accounts::read(root)?;
+        self.copy(root, &accounts, &self.dir, "")
+    }
+
+    fn copy(
+        &self,
+        root: &mut dyn Volume,
+        accounts: &Accounts,
+        dir: &Path,
+        relative: &str,
+    ) -> Result<usize> {
+        let mut entries = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+
+        let mut copied = 0;
+        for local in entries {
+            let name = local
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    anyhow!("{} isn't a valid UTF-8 name", local.display())
+                })?;
+            if relative.is_empty() && name == OWNERS {
+                continue;
+            }
+            let path = if relative.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{relative}/{name}")
+            };
+            let metadata = fs::symlink_metadata(&local)?;
+            let (uid, gid) = match self.owners.get(&path) {
+                Some(owner) => accounts.resolve(owner)?,
+                None => (0, 0),
+            };
+            let mode =
+                Mode::owned(metadata.permissions().mode() & 0o7777, uid, gid);
+
+            if metadata.is_symlink() {
+                let target = fs::read_link(&local)?;
+                let target = target.to_str().ok_or_else(|| {
+                    anyhow!("{} isn't a valid UTF-8 link", local.display())
+                })?;
+                root.symlink(&path, target)?;
+                copied += 1;
+            } else if metadata.is_dir() {
+                root.create_dir(&path, mode)?;
+                copied += self.copy(root, accounts, &local, &path)?;
+            } else if metadata.is_file() {
+                root.write(&path, &fs::read(&local)?, mode)?;
+                copied += 1;
+            } else {
+                bail!("{} isn't a file, directory or symlink", local.display())
+            }
+        }
+        Ok(copied)
+    }
+}
+
+/// Make sure every name (and link) in the overlay can be written to an image,
+/// before any are
+fn check(dir: &Path) -> Result<()> {
+    // The image's root is written through debugfs, whose quoted arguments
+    // can't hold quotes or line breaks
+    fn valid(name: Option<&str>) -> bool {
+        name.is_some_and(|name| {
+            !name.contains(|c: char| c == '"' || c.is_control())
+        })
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let local = entry?.path();
+        if !valid(local.file_name().and_then(|name| name.to_str())) {
+            bail!(
+                "{} can't be copied to an image, its name isn't UTF-8 or has \
+                 a quote or control character",
+                local.display()
+            )
+        }
+        let metadata = fs::symlink_metadata(&local)?;
+        if metadata.is_symlink() {
+            if !valid(fs::read_link(&local)?.to_str()) {
+                bail!(
+                    "{} can't be copied to an image, its target isn't UTF-8 \
+                     or has a quote or control character",
+                    local.display()
+                )
+            }
+        } else if metadata.is_dir() {
+            check(&local)?;
+        }
+    }
+    Ok(())
+}
+
+/// Users and groups as the image has them
+#[derive(Debug)]
+struct Accounts {
+    /// uid and primary gid by name
+    users: HashMap<String, (u32, u32)>,
+    groups: HashMap<String, u32>,
+}
+
+impl Accounts {
+    fn read(root: &mut dyn Volume) -> Result<Self> {
+        let passwd = root.read_to_string("etc/passwd")?.unwrap_or_default();
+        let group = root.read_to_string("etc/group")?.unwrap_or_default();
+        let fields =
+            |line: &str| line.split(':').map(str::to_owned).collect::<Vec<_>>();
+        Ok(Self {
+            users: passwd
+                .lines()
+                .map(fields)
+                .filter_map(|fields| {
+                    Some((
+                        fields.first()?.clone(),
+                        (
+                            fields.get(2)?.parse().ok()?,
+                            fields.get(3)?.parse().ok()?,
+                        ),
+                    ))
+                })
+                .collect(),
+            groups: group
+                .lines()
+                .map(fields)
+                .filter_map(|fields| {
+                    Some((
+                        fields.first()?.clone(),
+                        fields.get(2)?.parse().ok()?,
+                    ))
+                })
+                .collect(),
+        })
+    }
+
+    /// The uid and gid for `user[:group]`, either of which can be numeric,
+    /// defaulting to the user's primary group
+    fn resolve(&self, owner: &str) -> Result<(u32, u32)> {
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (owner, None),
+        };
+        let (uid, primary) = match (user.parse(), self.users.get(user)) {
+            (Ok(uid), _) => (uid, None),
+            (Err(_), Some(&(uid, gid))) => (uid, Some(gid)),
+            (Err(_), None) => bail!("The image has no user {user}"),
+        };
+        let gid = match group {
+            Some(group) => match (group.parse(), self.groups.get(group)) {
+                (Ok(gid), _) | (Err(_), Some(&gid)) => gid,
+                (Err(_), None) => bail!("The image has no group {group}"),
+            },
+            None => primary.unwrap_or(uid),
+        };
+        Ok((uid, gid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        os::unix::{ffi::OsStrExt as _, fs::symlink},
+    };
+
+    use super::*;
+
+    fn overlay(build: impl FnOnce(&Path)) -> Result<Overlay> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("etc/app")).unwrap();
+        fs::write(dir.path().join("etc/app/app.conf"), "").unwrap();
+        build(dir.path());
+        Overlay::load(dir.path().to_owned())
+    }
+
+    #[test]
+    fn loads_plain_names() {
+        let overlay = overlay(|dir| {
+            symlink("app/app.conf", dir.join("etc/app.conf")).unwrap();
+        })
+        .unwrap();
+        assert!(overlay.owners.is_empty());
+    }
+
+    #[test]
+    fn rejects_names_debugfs_cant_quote() {
+        for name in [
+            OsStr::new("a\"b"),
+            OsStr::new("a\nb"),
+            OsStr::from_bytes(b"\xff"),
+        ] {
+            let error = overlay(|dir| {
+                fs::write(dir.join("etc/app").join(name), "").unwrap();
+            })
+            .unwrap_err()
+            .to_string();
+            assert!(error.contains("etc/app/"), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_links_debugfs_cant_quote() {
+        let error =
+            overlay(|dir| symlink("a\"b", dir.join("etc/link")).unwrap())
+                .unwrap_err()
+                .to_string();
+        assert!(error.contains("etc/link"), "{error}");
+    }
+}
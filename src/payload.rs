@@ -0,0 +1,594 @@
+use std::{
+    cell::Cell,
+    fmt,
+    fs::File,
+    io::{
+        self, BufRead as _, BufReader, Read, Seek as _, SeekFrom, Take, Write,
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+use crate::utils;
+
+/// Marks the end of an executable with an embedded image
+pub(crate) const MAGIC: &[u8; 8] = b"PI_END\0\0";
+
+/// Size of the footer at the very end of a bundled executable
+pub(crate) const FOOTER_LEN: u64 = 32;
+
+/// Location of the embedded image and manifest within a bundled executable
+///
+/// Bundles are laid out as: executable, image, manifest, offset of the image
+/// (u64 LE), length of the image (u64 LE), length of the manifest (u64 LE),
+/// [MAGIC]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Footer {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    pub(crate) manifest_length: u64,
+}
+
+impl Footer {
+    /// Read the footer from the end of the file, leaves the cursor in an
+    /// unspecified position
+    pub(crate) fn read(file: &mut File) -> Result<Self> {
+        Self::probe(file)?.ok_or_else(|| {
+            anyhow!("Couldn't find an embedded image (missing end marker)")
+        })
+    }
+
+    /// As [Footer::read] but returns `None` for files without an embedded
+    /// image rather than failing
+    pub(crate) fn probe(file: &mut File) -> Result<Option<Self>> {
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Ok(None);
+        }
+        let _ = file.seek(SeekFrom::End(-FOOTER_LEN.cast_signed()))?;
+        let mut footer = [0; 32];
+        file.read_exact(&mut footer)?;
+        let (fields, magic) = footer.split_at(24);
+        if magic != MAGIC {
+            return Ok(None);
+        }
+        let field = |n: usize| -> Result<u64> {
+            Ok(u64::from_le_bytes(fields[n * 8..(n + 1) * 8].try_into()?))
+        };
+        let footer = Self {
+            offset: field(0)?,
+            length: field(1)?,
+            manifest_length: field(2)?,
+        };
+        let end = footer
+            .offset
+            .checked_add(footer.length)
+            .and_then(|end| end.checked_add(footer.manifest_length));
+        if end != Some(size - FOOTER_LEN) {
+            bail!(
+                "Embedded image footer is corrupt (offset {} + length {} + \
+                 manifest {} doesn't end at the footer)",
+                footer.offset,
+                footer.length,
+                footer.manifest_length
+            )
+        }
+        Ok(Some(footer))
+    }
+
+    pub(crate) fn write(self, mut out: impl Write) -> Result<()> {
+        out.write_all(&self.offset.to_le_bytes())?;
+        out.write_all(&self.length.to_le_bytes())?;
+        out.write_all(&self.manifest_length.to_le_bytes())?;
+        out.write_all(MAGIC)?;
+        Ok(())
+    }
+}
+
+/// Description of an embedded image, stored between the image and the footer
+/// as `key=value` lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) os: String,
+    pub(crate) release: String,
+    pub(crate) arch: String,
+    pub(crate) length: u64,
+    pub(crate) sha256: String,
+}
+
+impl Manifest {
+    /// Describe an image file, fields not given are guessed from Raspberry Pi
+    /// OS style file names (`2024-11-19-raspios-bookworm-arm64-lite.img`)
+    pub(crate) fn describe(
+        image: &Path,
+        os: Option<String>,
+        release: Option<String>,
+        arch: Option<String>,
+    ) -> Result<Self> {
+        let stem = image
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .unwrap_or_default();
+        let parts = stem.split('-').collect::<Vec<_>>();
+        let (guessed_release, rest) = if parts.len() > 3
+            && parts[..3].iter().all(|part| {
+                !part.is_empty() && part.chars().all(|c| c.is_ascii_digit())
+            }) {
+            (Some(parts[..3].join("-")), &parts[3..])
+        } else {
+            (None, &parts[..])
+        };
+        let guessed_arch = rest
+            .iter()
+            .find(|part| matches!(**part, "arm64" | "armhf" | "aarch64"))
+            .map(|arch| (*arch).to_owned());
+        let guessed_os = rest
+            .iter()
+            .filter(|part| !matches!(**part, "arm64" | "armhf" | "aarch64"))
+            .copied()
+            .collect::<Vec<_>>()
+            .join("-");
+
+        let file = File::open(image)
+            .with_context(|| format!("Failed to open {}", image.display()))?;
+        let length = file.metadata()?.len();
+        prompt!("Hashing {}...", image.display());
+        let sha256 = utils::sha256(file)?;
+        println!("Done");
+
+        Ok(Self {
+            os: os
+                .or(Some(guessed_os).filter(|os| !os.is_empty()))
+                .unwrap_or_else(|| String::from("unknown")),
+            release: release
+                .or(guessed_release)
+                .unwrap_or_else(|| String::from("unknown")),
+            arch: arch
+                .or(guessed_arch)
+                .unwrap_or_else(|| String::from("unknown")),
+            length,
+            sha256,
+        })
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Self> {
+        let mut os = None;
+        let mut release = None;
+        let mut arch = None;
+        let mut length = None;
+        let mut sha256 = None;
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("Invalid manifest line: {line}")
+            };
+            let value = value.to_owned();
+            match key {
+                "os" => os = Some(value),
+                "release" => release = Some(value),
+                "arch" => arch = Some(value),
+                "length" => length = Some(value.parse()?),
+                "sha256" => sha256 = Some(value),
+                // Unknown keys are ignored so newer bundles can still be read
+                _ => (),
+            }
+        }
+        let missing = |key| anyhow!("Manifest is missing {key}");
+        Ok(Self {
+            os: os.ok_or_else(|| missing("os"))?,
+            release: release.ok_or_else(|| missing("release"))?,
+            arch: arch.ok_or_else(|| missing("arch"))?,
+            length: length.ok_or_else(|| missing("length"))?,
+            sha256: sha256.ok_or_else(|| missing("sha256"))?,
+        })
+    }
+
+    pub(crate) fn serialize(&self) -> String {
+        format!(
+            "os={}\nrelease={}\narch={}\nlength={}\nsha256={}\n",
+            self.os, self.release, self.arch, self.length, self.sha256
+        )
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "OS:           {}", self.os)?;
+        writeln!(f, "Release:      {}", self.release)?;
+        writeln!(f, "Architecture: {}", self.arch)?;
+        writeln!(
+            f,
+            "Size:         {} ({} bytes)",
+            utils::format_size(self.length),
+            self.length
+        )?;
+        write!(f, "SHA-256:      {}", self.sha256)
+    }
+}
+
+/// How the bytes of a payload are stored, detected from their magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    None,
+    Xz,
+    Gzip,
+    Zip,
+}
+
+impl Compression {
+    fn detect(header: &[u8]) -> Self {
+        if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if header.starts_with(b"PK\x03\x04") {
+            Self::Zip
+        } else {
+            Self::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Xz => "xz",
+            Self::Gzip => "gzip",
+            Self::Zip => "zip",
+        })
+    }
+}
+
+/// An image, either standalone or embedded in a bundled executable, possibly
+/// compressed
+#[derive(Debug)]
+pub(crate) struct Payload {
+    path: PathBuf,
+    offset: u64,
+    pub(crate) length: u64,
+    pub(crate) manifest: Option<Manifest>,
+}
+
+impl Payload {
+    /// A standalone image file
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let length = File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?
+            .metadata()?
+            .len();
+        Ok(Self {
+            path: path.to_owned(),
+            offset: 0,
+            length,
+            manifest: None,
+        })
+    }
+
+    /// One line naming the image, from the manifest if it has one
+    pub(crate) fn summary(&self) -> String {
+        match &self.manifest {
+            Some(manifest) => format!(
+                "{} {} {}",
+                manifest.os, manifest.release, manifest.arch
+            ),
+            None => self.path.display().to_string(),
+        }
+    }
+
+    /// A standalone image file with a known manifest
+    pub(crate) fn from_file_with_manifest(
+        path: impl AsRef<Path>,
+        manifest: Manifest,
+    ) -> Result<Self> {
+        let payload = Self::from_file(path)?;
+        if manifest.length != payload.length {
+            bail!(
+                "{} is {} bytes but the manifest expects {}, the image is \
+                 probably truncated",
+                payload.path.display(),
+                payload.length,
+                manifest.length
+            )
+        }
+        Ok(Self {
+            manifest: Some(manifest),
+            ..payload
+        })
+    }
+
+    /// The image embedded in a bundled executable
+    pub(crate) fn embedded(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let Footer {
+            offset,
+            length,
+            manifest_length,
+        } = Footer::read(&mut file)?;
+        let _ = file.seek(SeekFrom::Start(offset + length))?;
+        let mut manifest = String::new();
+        let _ = file.take(manifest_length).read_to_string(&mut manifest)?;
+        let manifest = Manifest::parse(&manifest)
+            .context("Failed to parse the embedded image manifest")?;
+        if manifest.length != length {
+            bail!(
+                "Embedded image is {length} bytes but the manifest expects {}, \
+                 the bundle is probably truncated",
+                manifest.length
+            )
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            offset,
+            length,
+            manifest: Some(manifest),
+        })
+    }
+
+    /// Stream the stored bytes of the image from the start, without
+    /// decompressing them
+    pub(crate) fn open(&self) -> Result<Take<File>> {
+        let mut file = File::open(&self.path).with_context(|| {
+            format!("Failed to open {}", self.path.display())
+        })?;
+        let _ = file.seek(SeekFrom::Start(self.offset))?;
+        Ok(file.take(self.length))
+    }
+
+    /// The file the image is in and how far into it the image starts, if
+    /// it's stored uncompressed so can be read where it is
+    pub(crate) fn in_place(&self) -> Result<Option<(&Path, u64)>> {
+        Ok((self.compression()? == Compression::None)
+            .then_some((self.path.as_path(), self.offset)))
+    }
+
+    pub(crate) fn compression(&self) -> Result<Compression> {
+        let mut reader = BufReader::new(self.open()?);
+        Ok(Compression::detect(reader.fill_buf()?))
+    }
+
+    /// Run `f` with a reader producing the decompressed image
+    ///
+    /// The number of stored bytes consumed so far is kept in `consumed`, for
+    /// compressed images this is the only way to know how far through the
+    /// image the reader is
+    pub(crate) fn stream<T>(
+        &self,
+        consumed: &Cell<u64>,
+        f: impl FnOnce(&mut dyn Read) -> Result<T>,
+    ) -> Result<T> {
+        let mut reader = BufReader::new(Counted {
+            inner: self.open()?,
+            count: consumed,
+        });
+        match Compression::detect(reader.fill_buf()?) {
+            Compression::None => f(&mut reader),
+            Compression::Xz => f(&mut XzDecoder::new_multi_decoder(reader)),
+            Compression::Gzip => f(&mut MultiGzDecoder::new(reader)),
+            Compression::Zip => {
+                // Archives are read as a stream so embedded payloads don't
+                // need to be seekable, the first .img entry is used
+                while let Some(mut entry) =
+                    zip::read::read_zipfile_from_stream(&mut reader)?
+                {
+                    let is_image = Path::new(entry.name())
+                        .extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("img"));
+                    if entry.is_file() && is_image {
+                        return f(&mut entry);
+                    }
+                }
+                bail!("Zip archive doesn't contain an .img file")
+            }
+        }
+    }
+
+    /// Check the image against the checksum in its manifest (if it has one)
+    pub(crate) fn verify(&self) -> Result<()> {
+        let Some(manifest) = &self.manifest else {
+            return Ok(());
+        };
+        let actual = utils::sha256(self.open()?)?;
+        if actual != manifest.sha256 {
+            bail!(
+                "Image checksum mismatch (expected {}, got {actual}), the \
+                 image is corrupt",
+                manifest.sha256
+            )
+        }
+        Ok(())
+    }
+}
+
+struct Counted<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    fn bundle(image: &[u8], manifest: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        let executable = b"\x7fELF pretend executable";
+        file.write_all(executable).unwrap();
+        file.write_all(image).unwrap();
+        file.write_all(manifest).unwrap();
+        Footer {
+            offset: executable.len() as u64,
+            length: image.len() as u64,
+            manifest_length: manifest.len() as u64,
+        }
+        .write(&mut file)
+        .unwrap();
+        file
+    }
+
+    #[test]
+    fn footer_round_trips() {
+        let mut file = bundle(b"image", b"os=raspios\n");
+        assert_eq!(
+            Footer::read(&mut file).unwrap(),
+            Footer {
+                offset: 23,
+                length: 5,
+                manifest_length: 11,
+            }
+        );
+    }
+
+    #[test]
+    fn files_without_a_footer() {
+        let mut file = tempfile::tempfile().unwrap();
+        assert_eq!(Footer::probe(&mut file).unwrap(), None);
+        file.write_all(&[0; 64]).unwrap();
+        assert_eq!(Footer::probe(&mut file).unwrap(), None);
+        assert!(Footer::read(&mut file).is_err());
+    }
+
+    #[test]
+    fn corrupt_footer() {
+        let mut file = bundle(b"image", b"");
+        // Lose a byte of the image
+        let mut bytes = Vec::new();
+        let _ = file.seek(SeekFrom::Start(0)).unwrap();
+        let _ = file.read_to_end(&mut bytes).unwrap();
+        let _ = bytes.remove(0);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&bytes).unwrap();
+        assert!(Footer::probe(&mut file).is_err());
+    }
+
+    fn manifest() -> Manifest {
+        Manifest {
+            os: "raspios-bookworm-lite".to_owned(),
+            release: "2024-11-19".to_owned(),
+            arch: "arm64".to_owned(),
+            length: 5,
+            sha256: "ab".repeat(32),
+        }
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let manifest = manifest();
+        assert_eq!(Manifest::parse(&manifest.serialize()).unwrap(), manifest);
+        // Keys newer versions might add are skipped
+        let newer = format!("{}board=pi5\n", manifest.serialize());
+        assert_eq!(Manifest::parse(&newer).unwrap(), manifest);
+        assert!(Manifest::parse("os=raspios\n").is_err());
+        assert!(Manifest::parse("os raspios\n").is_err());
+    }
+
+    #[test]
+    fn embedded_manifest() {
+        let manifest = manifest();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pi");
+        let mut file = bundle(b"image", manifest.serialize().as_bytes());
+        let _ = file.seek(SeekFrom::Start(0)).unwrap();
+        let _ = io::copy(&mut file, &mut File::create(&path).unwrap()).unwrap();
+        let payload = Payload::embedded(&path).unwrap();
+        assert_eq!(payload.length, 5);
+        assert_eq!(payload.manifest, Some(manifest));
+        let mut image = String::new();
+        let _ = payload.open().unwrap().read_to_string(&mut image).unwrap();
+        assert_eq!(image, "image");
+    }
+
+    #[test]
+    fn describe_guesses_from_the_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join("2024-11-19-raspios-bookworm-arm64-lite.img");
+        fs::write(&path, b"image").unwrap();
+        let manifest = Manifest::describe(&path, None, None, None).unwrap();
+        assert_eq!(manifest.os, "raspios-bookworm-lite");
+        assert_eq!(manifest.release, "2024-11-19");
+        assert_eq!(manifest.arch, "arm64");
+        assert_eq!(manifest.length, 5);
+
+        let path = dir.path().join("custom.img");
+        fs::write(&path, b"image").unwrap();
+        let manifest =
+            Manifest::describe(&path, None, Some("1".to_owned()), None)
+                .unwrap();
+        assert_eq!(manifest.os, "custom");
+        assert_eq!(manifest.release, "1");
+        assert_eq!(manifest.arch, "unknown");
+    }
+
+    #[test]
+    fn detect_compression() {
+        for (header, compression) in [
+            (&b"\xfd7zXZ\0\0\x04"[..], Compression::Xz),
+            (b"\x1f\x8b\x08\0", Compression::Gzip),
+            (b"PK\x03\x04\x14\0", Compression::Zip),
+            (&[0; 512], Compression::None),
+            (b"\xfd7zX", Compression::None),
+            (b"", Compression::None),
+        ] {
+            assert_eq!(Compression::detect(header), compression);
+        }
+    }
+
+    #[test]
+    fn stream_decompresses() {
+        let image = b"boot sector and partitions".repeat(100);
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&image).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&image).unwrap();
+        let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("README.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("pi.img", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&image).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        for (stored, compression) in [
+            (image.clone(), Compression::None),
+            (xz.finish().unwrap(), Compression::Xz),
+            (gzip.finish().unwrap(), Compression::Gzip),
+            (zip.finish().unwrap().into_inner(), Compression::Zip),
+        ] {
+            let path = dir.path().join(compression.to_string());
+            fs::write(&path, &stored).unwrap();
+            let payload = Payload::from_file(&path).unwrap();
+            assert_eq!(payload.compression().unwrap(), compression);
+            let consumed = Cell::new(0);
+            let streamed = payload
+                .stream(&consumed, |reader| {
+                    let mut streamed = Vec::new();
+                    let _ = reader.read_to_end(&mut streamed)?;
+                    Ok(streamed)
+                })
+                .unwrap();
+            assert_eq!(streamed, image, "{compression}");
+            assert!(consumed.get() > 0);
+        }
+    }
+}
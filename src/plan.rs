@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, btree_map::Entry};
+
+use anyhow::Result;
+
+use crate::{
+    image::{BOOT_PARTITION, ROOT_PARTITION},
+    volume::{Mode, Volume},
+};
+
+/// Files whose changes are worth showing line by line
+const DIFFED: [&str; 3] = ["cmdline.txt", "etc/hosts", "etc/hostname"];
+
+/// What customizing an image changed, for pi image --dry-run
+#[derive(Debug, Default)]
+pub(crate) struct Plan(BTreeMap<(u32, String), Change>);
+
+/// The overall change to one path
+#[derive(Debug)]
+enum Change {
+    Write {
+        /// None if the file was created
+        before: Option<Vec<u8>>,
+        after: Vec<u8>,
+    },
+    Remove,
+    Symlink(String),
+}
+
+impl Plan {
+    /// `volume`, partition `number` of the image, recording changes made
+    /// through it in the plan
+    pub(crate) fn record<'a>(
+        &'a mut self,
+        number: u32,
+        volume: &'a mut dyn Volume,
+    ) -> Recording<'a> {
+        Recording {
+            plan: self,
+            number,
+            volume,
+        }
+    }
+
+    pub(crate) fn print(&self) {
+        if self.0.is_empty() {
+            println!("Nothing would be changed");
+            return;
+        }
+        let mut partition = None;
+        for ((number, path), change) in &self.0 {
+            if partition != Some(*number) {
+                partition = Some(*number);
+                match *number {
+                    BOOT_PARTITION => println!("Boot partition:"),
+                    ROOT_PARTITION => println!("Root partition:"),
+                    number => println!("Partition {number}:"),
+                }
+            }
+            match change {
+                Change::Write {
+                    before: None,
+                    after,
+                } => {
+                    println!("  created  {path}");
+                    if DIFFED.contains(&path.as_str()) {
+                        print_diff("", &String::from_utf8_lossy(after));
+                    }
+                }
+                Change::Write {
+                    before: Some(before),
+                    after,
+                } => {
+                    println!("  modified {path}");
+                    if DIFFED.contains(&path.as_str()) {
+                        print_diff(
+                            &String::from_utf8_lossy(before),
+                            &String::from_utf8_lossy(after),
+                        );
+                    }
+                }
+                Change::Remove => println!("  removed  {path}"),
+                Change::Symlink(target) => {
+                    println!("  linked   {path} -> {target}");
+                }
+            }
+        }
+    }
+}
+
+/// A [Volume] that notes down every change made through it
+pub(crate) struct Recording<'a> {
+    plan: &'a mut Plan,
+    number: u32,
+    volume: &'a mut dyn Volume,
+}
+
+impl Recording<'_> {
+    fn note(&mut self, path: &str, change: Change) {
+        match self.plan.0.entry((self.number, path.to_owned())) {
+            Entry::Occupied(mut entry) => {
+                // What a file was like before the first change is what
+                // matters
+                let change = match (entry.get_mut(), change) {
+                    (
+                        Change::Write { before, .. },
+                        Change::Write { after, .. },
+                    ) => Change::Write {
+                        before: before.take(),
+                        after,
+                    },
+                    // Created and removed again, so nothing changed
+                    (Change::Write { before: None, .. }, Change::Remove) => {
+                        let _ = entry.remove();
+                        return;
+                    }
+                    (_, change) => change,
+                };
+                let _ = entry.insert(change);
+            }
+            Entry::Vacant(entry) => {
+                let _ = entry.insert(change);
+            }
+        }
+    }
+}
+
+impl Volume for Recording<'_> {
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        self.volume.read(path)
+    }
+
+    fn write(&mut self, path: &str, contents: &[u8], mode: Mode) -> Result<()> {
+        let before = self.volume.read(path)?;
+        self.volume.write(path, contents, mode)?;
+        if before.as_deref() != Some(contents) {
+            self.note(
+                path,
+                Change::Write {
+                    before,
+                    after: contents.to_vec(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &str, mode: Mode) -> Result<()> {
+        // Directories show up through the files written to them
+        self.volume.create_dir(path, mode)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<bool> {
+        let removed = self.volume.remove(path)?;
+        if removed {
+            self.note(path, Change::Remove);
+        }
+        Ok(removed)
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        self.volume.symlink(path, target)?;
+        self.note(path, Change::Symlink(target.to_owned()));
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Recording<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recording")
+            .field("number", &self.number)
+            .finish_non_exhaustive()
+    }
+}
+
+fn print_diff(before: &str, after: &str) {
+    for line in diff(before, after) {
+        println!("{line}");
+    }
+}
+
+/// The lines removed from `before` and added in `after`, in order
+fn diff(before: &str, after: &str) -> Vec<String> {
+    let before = before.lines().collect::<Vec<_>>();
+    let after = after.lines().collect::<Vec<_>>();
+    // common[i][j] is the length of the longest common subsequence of
+    // before[i..] and after[j..]
+    let mut common = vec![vec![0_usize; after.len() + 1]; before.len() + 1];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            common[i][j] = if before[i] == after[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() || j < after.len() {
+        if i < before.len() && j < after.len() && before[i] == after[j] {
+            i += 1;
+            j += 1;
+        } else if j == after.len()
+            || (i < before.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            lines.push(format!("    - {}", before[i]));
+            i += 1;
+        } else {
+            lines.push(format!("    + {}", after[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::volume::{Changes, CopyOnWrite, Mounted};
+
+    #[test]
+    fn diff_shows_changed_lines_in_order() {
+        assert_eq!(
+            diff(
+                "127.0.0.1\tlocalhost\n127.0.1.1\traspberrypi\n",
+                "127.0.0.1\tlocalhost\n127.0.1.1\tsensor\n"
+            ),
+            ["    - 127.0.1.1\traspberrypi", "    + 127.0.1.1\tsensor"]
+        );
+        assert_eq!(diff("a\nb\nc\n", "b\nc\nd\n"), ["    - a", "    + d"]);
+        assert_eq!(diff("", "sensor"), ["    + sensor"]);
+        assert!(diff("same\n", "same").is_empty());
+    }
+
+    #[test]
+    fn notes_keep_the_first_before_and_last_after() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("hostname"), "raspberrypi").unwrap();
+        std::fs::write(dir.path().join("hosts"), "127.0.0.1").unwrap();
+        let mut base = Mounted(dir.path().to_owned());
+        let mut changes = Changes::default();
+        let mut volume = CopyOnWrite {
+            base: &mut base,
+            changes: &mut changes,
+        };
+        let mut plan = Plan::default();
+        let mut root = plan.record(ROOT_PARTITION, &mut volume);
+        root.write("hostname", b"first", Mode::FILE).unwrap();
+        root.write("hostname", b"sensor", Mode::FILE).unwrap();
+        root.write("new", b"one", Mode::FILE).unwrap();
+        root.write("new", b"two", Mode::FILE).unwrap();
+        // Writing what's already there isn't a change
+        root.write("same", b"", Mode::FILE).unwrap();
+        root.write("same", b"", Mode::FILE).unwrap();
+        root.write("temporary", b"", Mode::FILE).unwrap();
+        assert!(root.remove("temporary").unwrap());
+        assert!(root.remove("hosts").unwrap());
+
+        let changes = plan
+            .0
+            .iter()
+            .map(|((number, path), change)| {
+                assert_eq!(*number, ROOT_PARTITION);
+                (path.as_str(), change)
+            })
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            changes[..],
+            [
+                ("hostname", Change::Write {
+                    before: Some(before),
+                    after: hostname,
+                }),
+                ("hosts", Change::Remove),
+                ("new", Change::Write {
+                    before: None,
+                    after: new,
+                }),
+                ("same", Change::Write { before: None, .. }),
+            ] if before == b"raspberrypi" && hostname == b"sensor" && new == b"two"
+        ));
+        // The directory was only read
+        assert_eq!(
+            std::fs::read(dir.path().join("hostname")).unwrap(),
+            b"raspberrypi"
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+use crate::{
+    boot_config::Setting, locale::Locale, os_release::OsProfile,
+    static_ip::Address,
+};
+
+/// Everything needed to customize an image, loaded from a TOML file so
+/// imaging can run without prompting
+///
+/// ```toml
+/// hostname = "kitchen"
+/// image = "bookworm-lite"
+/// os = "raspbian"
+/// first_boot = "setup.sh"
+/// packages = ["vim", "git"]
+///
+/// [wifi]
+/// country = "GB"
+/// networks = ["office", "lab"]
+/// ssid = "Home"
+/// psk_file = "home.psk"
+///
+/// [user]
+/// name = "alice"
+/// password_hash = "$6$..."
+///
+/// [network]
+/// address = ["192.168.1.20/24"]
+/// gateway = ["192.168.1.1"]
+/// dns = ["192.168.1.1"]
+///
+/// [locale]
+/// timezone = "Europe/London"
+/// lang = "en_GB.UTF-8"
+/// keyboard = "gb"
+///
+/// [boot]
+/// config = ["dtparam=audio=off", "cmdline:quiet"]
+/// ```
+///
+/// Every field is optional, anything missing is taken from the command line
+/// or prompted for
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Profile {
+    pub(crate) hostname: Option<String>,
+    /// An image file, relative to the profile, or the name of a cached image
+    pub(crate) image: Option<String>,
+    /// raspbian or cloud-init, detected from the image if not given
+    pub(crate) os: Option<OsProfile>,
+    /// A script to run on first boot, relative to the profile
+    pub(crate) first_boot: Option<PathBuf>,
+    /// Installed on first boot
+    pub(crate) packages: Vec<String>,
+    pub(crate) wifi: Wifi,
+    pub(crate) user: User,
+    pub(crate) network: Network,
+    pub(crate) locale: Locale,
+    pub(crate) boot: Boot,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Wifi {
+    /// Regulatory country code
+    pub(crate) country: Option<String>,
+    /// Networks saved with `pi networks add`
+    pub(crate) networks: Vec<String>,
+    /// Prefixed with `hex:` for SSIDs given in hex
+    pub(crate) ssid: Option<String>,
+    /// Either the passphrase or the 64 hex digit PSK derived from it
+    pub(crate) psk: Option<String>,
+    /// Read the PSK from a file rather than keeping it in the profile,
+    /// relative to the profile
+    pub(crate) psk_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct User {
+    pub(crate) name: Option<String>,
+    /// Read the password from a file, relative to the profile
+    pub(crate) password_file: Option<PathBuf>,
+    /// An already hashed password, as from `openssl passwd -6`
+    pub(crate) password_hash: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Network {
+    /// Defaults to eth0
+    pub(crate) interface: Option<String>,
+    /// At most one IPv4 and one IPv6 address, with prefix lengths
+    pub(crate) address: Vec<Address>,
+    pub(crate) gateway: Vec<IpAddr>,
+    pub(crate) dns: Vec<IpAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Boot {
+    /// Applied before any given with --boot-config
+    pub(crate) config: Vec<Setting>,
+}
+
+impl Profile {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| {
+            format!("Failed to read profile {}", path.display())
+        })?;
+        let mut profile: Self =
+            toml::from_str(&contents).with_context(|| {
+                format!("Failed to parse profile {}", path.display())
+            })?;
+        if let Some(dir) = path.parent() {
+            profile.wifi.psk_file =
+                profile.wifi.psk_file.map(|file| dir.join(file));
+            profile.user.password_file =
+                profile.user.password_file.map(|file| dir.join(file));
+            profile.first_boot = profile.first_boot.map(|file| dir.join(file));
+            // Cached image names can't have a /, so anything with one (or
+            // naming a file next to the profile) is a path
+            profile.image = profile.image.map(|image| {
+                let path = dir.join(&image);
+                if image.contains('/') || path.is_file() {
+                    path.into_os_string().into_string().unwrap_or(image)
+                } else {
+                    image
+                }
+            });
+        }
+        Ok(profile)
+    }
+}
+
+impl Wifi {
+    /// The PSK, from the file if one was given
+    pub(crate) fn psk(&self) -> Result<Option<String>> {
+        match &self.psk_file {
+            Some(file) => read_secret(file).map(Some),
+            None => Ok(self.psk.clone()),
+        }
+    }
+}
+
+/// Read a password or PSK from `path`, ignoring the trailing newline
+pub(crate) fn read_secret(path: &Path) -> Result<String> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(contents.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(contents: &str) -> (tempfile::TempDir, Profile) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pi.toml");
+        fs::write(&path, contents).unwrap();
+        let profile = Profile::load(&path).unwrap();
+        (dir, profile)
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_profile() {
+        let (dir, profile) = load(
+            r#"
+            image = "./images/bookworm.img"
+            first_boot = "setup.sh"
+
+            [wifi]
+            psk_file = "secrets/home.psk"
+
+            [user]
+            password_file = "/etc/pi/password"
+            "#,
+        );
+        let dir = dir.path();
+        assert_eq!(
+            profile.image.map(PathBuf::from),
+            Some(dir.join("images/bookworm.img"))
+        );
+        assert_eq!(profile.first_boot, Some(dir.join("setup.sh")));
+        assert_eq!(profile.wifi.psk_file, Some(dir.join("secrets/home.psk")));
+        assert_eq!(
+            profile.user.password_file,
+            Some(PathBuf::from("/etc/pi/password"))
+        );
+    }
+
+    #[test]
+    fn image_next_to_the_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("bookworm.img"), "").unwrap();
+        let path = dir.path().join("pi.toml");
+        fs::write(&path, "image = \"bookworm.img\"").unwrap();
+        assert_eq!(
+            Profile::load(&path).unwrap().image.map(PathBuf::from),
+            Some(dir.path().join("bookworm.img"))
+        );
+    }
+
+    #[test]
+    fn cached_image_names_are_kept() {
+        let (_dir, profile) = load(r#"image = "bookworm-lite""#);
+        assert_eq!(profile.image.as_deref(), Some("bookworm-lite"));
+    }
+}
@@ -8,7 +8,7 @@ use anyhow::Result;
 use argh::FromArgs;
 use command_ext::CommandExt as _;
 
-use crate::{identity::Identity, resolve};
+use crate::{identity::Identity, resolve, users};
 
 /// Retrieve a list of files from the pi
 #[derive(Debug, FromArgs)]
@@ -44,13 +44,14 @@ pub fn pull(
 ) -> Result<()> {
     let name = name.as_ref();
     let ip = resolve(name)?;
+    let user = users::get(name)?;
     let files = files
         .iter()
         .map(AsRef::as_ref)
         .map(Path::as_os_str)
         .collect::<Vec<_>>()
         .join(&OsString::from(" "));
-    let mut full_src = OsString::from(format!("pi@{ip}:"));
+    let mut full_src = OsString::from(format!("{user}@{ip}:"));
     full_src.push(files);
     Command::new("scp")
         .arg("-i")
@@ -8,7 +8,7 @@ use anyhow::Result;
 use argh::FromArgs;
 use command_ext::CommandExt;
 
-use crate::{identity::Identity, resolve};
+use crate::{identity::Identity, resolve, users};
 
 /// Send a list of files to the pi
 #[derive(Debug, FromArgs)]
@@ -43,7 +43,8 @@ pub fn push(
 ) -> Result<()> {
     let name = name.as_ref();
     let ip = resolve(name)?;
-    let mut full_dst = OsString::from(format!("pi@{ip}:"));
+    let user = users::get(name)?;
+    let mut full_dst = OsString::from(format!("{user}@{ip}:"));
     full_dst.push(dst.as_ref());
     Command::new("scp")
         .arg("-i")
@@ -1,18 +1,13 @@
-use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::Write as _,
-    net::Ipv4Addr,
-    process::Command,
-};
+use std::{net::Ipv4Addr, process::Command};
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Result, bail};
 use argh::FromArgs;
 use command_ext::CommandExt as _;
 
 use crate::{
-    identity::{Created, Identity, Unknown},
-    resolve,
+    host_keys,
+    identity::{Created, Identity},
+    resolve, users,
     utils::{self, Prompt},
 };
 
@@ -26,9 +21,13 @@ pub(crate) struct Args {
 }
 
 pub(crate) fn main(Args { name }: Args) -> Result<()> {
+    if let Some(keys) = host_keys::recorded(&name)? {
+        return register_baked(&name, &keys);
+    }
+
     let id = match Identity::new_unknown(&name)?.exists() {
         Ok(id) => check_reuse(&name, id)?,
-        Err(id) => generate_id(&name, id)?,
+        Err(id) => id.generate(&name)?,
     };
 
     prompt!(
@@ -37,16 +36,14 @@ pub(crate) fn main(Args { name }: Args) -> Result<()> {
     let ip = resolve::probe(&name)?;
     println!("Done");
 
-    let mut known_hosts = read_known_hosts()?;
-    if known_hosts.contains_key(&ip.to_string()) {
+    if host_keys::is_known(ip)? {
         prompt!("IP Address {ip} is already in known_hosts, remove?: [Y/n]: ");
         if utils::read_prompt(Prompt::Yes)?.is_yes() {
-            drop(known_hosts.remove(&ip.to_string()));
-            write_known_hosts(known_hosts)?;
+            host_keys::forget(ip)?;
         }
     }
 
-    send_id(&id, ip)?;
+    send_id(&name, &id, ip)?;
 
     prompt!("Identity installed, running full IP resolution...");
     let _ = resolve(&name)?;
@@ -55,6 +52,28 @@ pub(crate) fn main(Args { name }: Args) -> Result<()> {
     Ok(())
 }
 
+/// Register a pi whose image already had its identity and host keys baked
+/// in, so there's nothing to copy and the host keys are already known
+fn register_baked(name: &str, keys: &[String]) -> Result<()> {
+    let _ = Identity::private(name).map_err(|e| {
+        e.context(format!("{name} was imaged with an identity that's gone"))
+    })?;
+
+    prompt!(
+        "Attempting partial IP resolution for {name}. This may take a while...",
+    );
+    let ip = resolve::probe(name)?;
+    println!("Done");
+
+    host_keys::trust(ip, keys)?;
+
+    prompt!("Running full IP resolution...");
+    let _ = resolve(name)?;
+    println!("Done");
+
+    Ok(())
+}
+
 fn check_reuse(name: &str, id: Identity<Created>) -> Result<Identity<Created>> {
     prompt!("Found existing identity for {}, reuse? [Y/n]: ", name);
     if utils::read_prompt(Prompt::Yes)?.is_yes() {
@@ -64,64 +83,18 @@ fn check_reuse(name: &str, id: Identity<Created>) -> Result<Identity<Created>> {
     prompt!("Overwrite previous identity for {}? [y/N]: ", name);
     if utils::read_prompt(Prompt::No)?.is_yes() {
         let id = id.delete()?;
-        return generate_id(name, id);
+        return id.generate(name);
     }
 
     bail!("Aborting identity creation")
 }
 
-fn generate_id(name: &str, id: Identity<Unknown>) -> Result<Identity<Created>> {
-    let success = Command::new("ssh-keygen")
-        .args(["-t", "rsa"]) // Key format
-        .args(["-N", ""]) // No password
-        .args(["-C", &format!("Auto-generated key for {name}.local")]) // Comment
-        .arg("-f")
-        .arg(&id.private) // Key location
-        .status()?
-        .success();
-    if !success {
-        bail!("ssh-keygen failed")
-    }
-    Ok(id
-        .exists()
-        .ok()
-        .expect("Identity should exist because we just created it"))
-}
-
-fn read_known_hosts() -> Result<HashMap<String, Vec<String>>> {
-    let text =
-        fs::read_to_string(utils::home()?.join(".ssh").join("known_hosts"))?;
-    Ok(text
-        .lines()
-        .map(|s| s.split_once(char::is_whitespace))
-        .collect::<Option<Vec<_>>>()
-        .ok_or_else(|| anyhow!("Failed to parse known_hosts"))?
-        .into_iter()
-        .map(|(ip, key)| Ok((ip, key)))
-        .collect::<Result<Vec<_>>>()?
-        .into_iter()
-        .fold(HashMap::new(), |mut map, (ip, key)| {
-            map.entry(ip.to_string()).or_default().push(key.to_string());
-            map
-        }))
-}
-
-fn write_known_hosts(data: HashMap<String, Vec<String>>) -> Result<()> {
-    let mut known_hosts =
-        File::create(utils::home()?.join(".ssh").join("known_hosts"))?;
-    for (ip, keys) in data {
-        for key in keys {
-            writeln!(known_hosts, "{ip} {key}")?;
-        }
-    }
-    Ok(())
-}
-
-fn send_id(id: &Identity<Created>, ip: Ipv4Addr) -> Result<()> {
+fn send_id(name: &str, id: &Identity<Created>, ip: Ipv4Addr) -> Result<()> {
+    let user = users::get(name)?;
     Command::new("ssh-copy-id")
         .arg("-i")
         .arg(&id.public)
-        .arg(format!("pi@{ip}"))
+        .arg(format!("{user}@{ip}"))
         .check_status()?;
     Ok(())
 }
@@ -10,7 +10,7 @@ use anyhow::{Context, Result, anyhow, bail};
 use argh::FromArgs;
 use command_ext::CommandExt;
 
-use crate::{identity::Identity, utils};
+use crate::{host_keys, identity::Identity, users, utils};
 
 const SSH_DB: &str = "ssh_db";
 
@@ -45,16 +45,27 @@ pub fn resolve(name: impl AsRef<str>) -> Result<Ipv4Addr> {
     };
 
     if need_new_ip {
-        let _ = db.insert(
-            String::from(name),
-            probe(name).context("IP probe failed")?,
-        );
+        let ip = probe(name).context("IP probe failed")?;
+        // Pis imaged with known host keys stay authenticated when their
+        // address changes
+        if let Some(keys) = host_keys::recorded(name)? {
+            host_keys::trust(ip, &keys)?;
+        }
+        let _ = db.insert(String::from(name), ip);
     }
 
     save_db(&db).context("Failed to save IP Database")?;
     Ok(db[name])
 }
 
+/// Point `name` at `ip` without checking it, for pis that can't be probed
+/// for
+pub(crate) fn set(name: &str, ip: Ipv4Addr) -> Result<()> {
+    let mut db = load_db().context("Failed to load IP Database")?;
+    let _ = db.insert(String::from(name), ip);
+    save_db(&db).context("Failed to save IP Database")
+}
+
 pub(crate) fn probe(name: &str) -> Result<Ipv4Addr> {
     loop {
         if let Some(result) = try_probe(name)? {
@@ -66,7 +77,7 @@ pub(crate) fn probe(name: &str) -> Result<Ipv4Addr> {
 fn ssh_works(name: &str, ip: Ipv4Addr) -> Result<bool> {
     // Not using run_on_pi since we can't go through resolve
     let output = Command::new("hostname")
-        .run_on_remote("pi", ip, Identity::private(name)?)
+        .run_on_remote(&users::get(name)?, ip, Identity::private(name)?)
         .check_output()?;
     Ok(output.trim() == name)
 }
@@ -91,7 +102,7 @@ fn try_probe(name: &str) -> Result<Option<Ipv4Addr>> {
     Ok(Some(parts[1].parse()?))
 }
 
-fn load_db() -> Result<HashMap<String, Ipv4Addr>> {
+pub(crate) fn load_db() -> Result<HashMap<String, Ipv4Addr>> {
     let db = utils::app_config()?.join(SSH_DB);
     let contents = match fs::read_to_string(&db) {
         Ok(contents) => contents,
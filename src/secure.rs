@@ -28,10 +28,11 @@ pub(crate) fn main(Args { name }: Args) -> Result<()> {
     let mut new_sshd = File::create(&new_sshd_path)?;
     writeln!(new_sshd, "{sshd}\nPasswordAuthentication no")?;
 
+    let pi_config = utils::pi_config(&name)?;
     utils::ensure_pi_config(&name)?;
-    push(&name, &[new_sshd_path], utils::PI_CONFIG)?;
+    push(&name, &[new_sshd_path], &pi_config)?;
     Command::new("mv")
-        .arg(format!("{}/sshd_config", utils::PI_CONFIG))
+        .arg(format!("{pi_config}/sshd_config"))
         .arg("/etc/ssh/sshd_config")
         .run_as_root()
         .run_on_pi(&name)?
@@ -58,7 +59,7 @@ fn unmount(path: impl AsRef<Path>) {
         if success {
             Ok(())
         } else {
-            bail!("fusermount -u {path:?} failed")
+            bail!("fusermount -u {} failed", path.display())
         }
     }
     let path = path.as_ref();
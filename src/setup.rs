@@ -26,7 +26,7 @@ pub(crate) fn main(Args { name, script }: Args) -> Result<()> {
     if !script.is_file() {
         bail!("{} does not exist or isn't a file", script.display())
     }
-    let target = format!("{}/pi.sh", utils::PI_CONFIG);
+    let target = format!("{}/pi.sh", utils::pi_config(&name)?);
 
     utils::ensure_pi_config(&name)?;
     push(&name, &[script], &target)?;
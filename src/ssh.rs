@@ -3,7 +3,7 @@ use std::process::{Command, ExitStatus};
 use anyhow::Result;
 use argh::FromArgs;
 
-use crate::{CommandExt as _, Identity, resolve};
+use crate::{CommandExt as _, Identity, resolve, users};
 
 /// SSH wrapper for managed pis
 #[derive(Debug, FromArgs)]
@@ -21,11 +21,12 @@ pub(crate) fn main(Args { name, cmd }: Args) -> Result<ExitStatus> {
         Ok(Command::new(cmd).args(args).run_on_pi(&name)?.status()?)
     } else {
         let ip = resolve(&name)?;
+        let user = users::get(&name)?;
         let key_file = Identity::private(name)?;
         Ok(Command::new("ssh")
             .arg("-i")
             .arg(key_file)
-            .arg(format!("pi@{ip}"))
+            .arg(format!("{user}@{ip}"))
             .status()?)
     }
 }
@@ -0,0 +1,245 @@
+use std::{
+    fmt::{self, Write as _},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use anyhow::{Error, Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::volume::{Mode, Volume};
+
+const DHCPCD_CONF: &str = "etc/dhcpcd.conf";
+/// The wired interface on every pi that has one
+pub(crate) const DEFAULT_INTERFACE: &str = "eth0";
+
+/// An address with its prefix length, as in 192.168.1.20/24
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Address {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, prefix) = s.split_once('/').ok_or_else(|| {
+            anyhow!("{s} has no prefix length (as in 192.168.1.20/24)")
+        })?;
+        let ip = ip.parse::<IpAddr>()?;
+        let prefix = prefix.parse::<u8>()?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            bail!("Invalid prefix length /{prefix} for {ip}")
+        }
+        Ok(Self { ip, prefix })
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// Fixed addressing for one interface, in place of DHCP and SLAAC
+///
+/// Either family without an address is still configured automatically
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StaticIp {
+    pub(crate) interface: String,
+    v4: Option<(Address, Option<IpAddr>)>,
+    v6: Option<(Address, Option<IpAddr>)>,
+    dns: Vec<IpAddr>,
+}
+
+impl StaticIp {
+    /// Check the addresses and gateways fit together, None if there are no
+    /// addresses
+    pub(crate) fn new(
+        interface: String,
+        addresses: &[Address],
+        gateways: &[IpAddr],
+        dns: Vec<IpAddr>,
+    ) -> Result<Option<Self>> {
+        if addresses.is_empty() {
+            if !gateways.is_empty() || !dns.is_empty() {
+                bail!("A gateway or DNS server needs a static --address")
+            }
+            return Ok(None);
+        }
+        if interface.is_empty()
+            || !interface.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            bail!("Invalid interface {interface}")
+        }
+
+        let family = |v4: bool| -> Result<Option<(Address, Option<IpAddr>)>> {
+            let name = if v4 { "IPv4" } else { "IPv6" };
+            let mut addresses = addresses
+                .iter()
+                .filter(|address| address.ip.is_ipv4() == v4);
+            let mut gateways =
+                gateways.iter().filter(|gateway| gateway.is_ipv4() == v4);
+            let address = addresses.next();
+            let gateway = gateways.next();
+            if addresses.next().is_some() || gateways.next().is_some() {
+                bail!("Only one {name} address and gateway can be given")
+            }
+            match (address, gateway) {
+                (Some(&address), gateway) => {
+                    Ok(Some((address, gateway.copied())))
+                }
+                (None, Some(gateway)) => {
+                    bail!("The gateway {gateway} needs a static {name} address")
+                }
+                (None, None) => Ok(None),
+            }
+        };
+        Ok(Some(Self {
+            interface,
+            v4: family(true)?,
+            v6: family(false)?,
+            dns,
+        }))
+    }
+
+    pub(crate) fn has_ipv6(&self) -> bool {
+        self.v6.is_some()
+    }
+
+    pub(crate) fn is_wifi(&self) -> bool {
+        self.interface.starts_with("wlan")
+    }
+
+    /// The `[ipv4]` and `[ipv6]` sections of a NetworkManager keyfile
+    pub(crate) fn keyfile(static_ip: Option<&Self>) -> String {
+        let mut conf = String::new();
+        for (section, v4, family) in [
+            ("ipv4", true, static_ip.and_then(|ip| ip.v4)),
+            ("ipv6", false, static_ip.and_then(|ip| ip.v6)),
+        ] {
+            let _ = writeln!(conf);
+            let _ = writeln!(conf, "[{section}]");
+            if let Some((address, gateway)) = family {
+                let _ = writeln!(conf, "method=manual");
+                let _ = writeln!(conf, "address1={address}");
+                if let Some(gateway) = gateway {
+                    let _ = writeln!(conf, "gateway={gateway}");
+                }
+            } else {
+                let _ = writeln!(conf, "method=auto");
+            }
+            // Servers are given per family, alongside any from DHCP for a
+            // family without a static address
+            let mut dns = static_ip
+                .into_iter()
+                .flat_map(|ip| &ip.dns)
+                .filter(|dns| dns.is_ipv4() == v4)
+                .peekable();
+            if dns.peek().is_some() {
+                let _ = write!(conf, "dns=");
+                for server in dns {
+                    let _ = write!(conf, "{server};");
+                }
+                let _ = writeln!(conf);
+            }
+        }
+        conf
+    }
+
+    /// A NetworkManager connection for a wired interface
+    pub(crate) fn ethernet(&self) -> (String, String) {
+        let mut conf = String::new();
+        let _ = writeln!(conf, "[connection]");
+        let _ = writeln!(conf, "id={}", self.interface);
+        let _ = writeln!(conf, "type=ethernet");
+        let _ = writeln!(conf, "interface-name={}", self.interface);
+        let _ = writeln!(conf, "autoconnect=true");
+        let _ = writeln!(conf);
+        let _ = writeln!(conf, "[ethernet]");
+        conf.push_str(&Self::keyfile(Some(self)));
+        (format!("{}.nmconnection", self.interface), conf)
+    }
+
+    /// An interface's addressing in netplan's YAML (what cloud-init's
+    /// network-config is), each line starting with `indent`
+    pub(crate) fn netplan(static_ip: Option<&Self>, indent: &str) -> String {
+        let mut conf = String::new();
+        let v4 = static_ip.and_then(|ip| ip.v4);
+        let _ = writeln!(conf, "{indent}dhcp4: {}", v4.is_none());
+        let families = [v4, static_ip.and_then(|ip| ip.v6)]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        if !families.is_empty() {
+            let _ = writeln!(conf, "{indent}addresses:");
+            for (address, _) in &families {
+                let _ = writeln!(conf, "{indent}  - \"{address}\"");
+            }
+        }
+        let mut gateways = families
+            .iter()
+            .filter_map(|(_, gateway)| *gateway)
+            .peekable();
+        if gateways.peek().is_some() {
+            let _ = writeln!(conf, "{indent}routes:");
+            for gateway in gateways {
+                let _ = writeln!(conf, "{indent}  - to: default");
+                let _ = writeln!(conf, "{indent}    via: \"{gateway}\"");
+            }
+        }
+        if let Some(ip) = static_ip
+            && !ip.dns.is_empty()
+        {
+            let _ = writeln!(conf, "{indent}nameservers:");
+            let _ = writeln!(conf, "{indent}  addresses:");
+            for server in &ip.dns {
+                let _ = writeln!(conf, "{indent}    - \"{server}\"");
+            }
+        }
+        conf
+    }
+
+    /// Add the addressing to dhcpcd.conf, for images from before
+    /// NetworkManager
+    ///
+    /// dhcpcd has no static IPv6 gateway, it's always taken from router
+    /// advertisements
+    pub(crate) fn write_dhcpcd(&self, root: &mut dyn Volume) -> Result<()> {
+        let mut conf = root
+            .read_to_string(DHCPCD_CONF)?
+            .ok_or_else(|| anyhow!("The image has no /{DHCPCD_CONF}"))?;
+        let _ = writeln!(conf);
+        let _ = writeln!(conf, "interface {}", self.interface);
+        if let Some((address, gateway)) = self.v4 {
+            let _ = writeln!(conf, "static ip_address={address}");
+            if let Some(gateway) = gateway {
+                let _ = writeln!(conf, "static routers={gateway}");
+            }
+        }
+        if let Some((address, _)) = self.v6 {
+            let _ = writeln!(conf, "static ip6_address={address}");
+        }
+        if !self.dns.is_empty() {
+            let dns = self
+                .dns
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let _ = writeln!(conf, "static domain_name_servers={dns}");
+        }
+        root.write(DHCPCD_CONF, conf.as_bytes(), Mode::FILE)
+    }
+}
@@ -0,0 +1,24 @@
+use anyhow::Result;
+
+use crate::volume::{Mode, Volume};
+
+const SYSTEM: &str = "etc/systemd/system";
+const MULTI_USER_WANTS: &str = "etc/systemd/system/multi-user.target.wants";
+
+/// Install `unit` as `name` and start it on boot, as `systemctl enable` would
+/// for a unit wanted by multi-user.target
+pub(crate) fn enable(
+    root: &mut dyn Volume,
+    name: &str,
+    unit: &str,
+) -> Result<()> {
+    root.create_dir(MULTI_USER_WANTS, Mode::DIR)?;
+    let path = format!("{SYSTEM}/{name}");
+    root.write(&path, unit.as_bytes(), Mode::FILE)?;
+    root.symlink(&format!("{MULTI_USER_WANTS}/{name}"), &format!("/{path}"))
+}
+
+/// Stop the unit `name` from starting on boot, returning whether it would have
+pub(crate) fn disable(root: &mut dyn Volume, name: &str) -> Result<bool> {
+    root.remove(&format!("{MULTI_USER_WANTS}/{name}"))
+}
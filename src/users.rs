@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Write as _},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+
+use crate::utils;
+
+const USERS_DB: &str = "users";
+/// The login on images that predate per-pi users
+pub(crate) const DEFAULT_USER: &str = "pi";
+
+/// The user to log in to `name` as
+pub(crate) fn get(name: &str) -> Result<String> {
+    let db = load_db().context("Failed to load users database")?;
+    Ok(db
+        .get(name)
+        .cloned()
+        .unwrap_or_else(|| String::from(DEFAULT_USER)))
+}
+
+/// Record that `name` was imaged with `user` as its login
+pub(crate) fn set(name: &str, user: &str) -> Result<()> {
+    let mut db = load_db().context("Failed to load users database")?;
+    let _ = db.insert(String::from(name), String::from(user));
+    save_db(&db).context("Failed to save users database")
+}
+
+/// Check `user` is a username the image's adduser will accept
+pub(crate) fn validate(user: &str) -> Result<()> {
+    let mut chars = user.chars();
+    let valid = user.len() <= 32
+        && chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'
+        });
+    if !valid {
+        bail!(
+            "Invalid username {user} (lowercase letters, digits, - and _, \
+             starting with a letter)"
+        )
+    }
+    Ok(())
+}
+
+fn load_db() -> Result<HashMap<String, String>> {
+    let db = utils::app_config()?.join(USERS_DB);
+    let contents = match fs::read_to_string(&db) {
+        Ok(contents) => contents,
+        Err(e) => {
+            if let ErrorKind::NotFound = e.kind() {
+                return Ok(HashMap::new());
+            }
+            return Err(
+                anyhow!(e).context(format!("Failed to read {}", db.display()))
+            );
+        }
+    };
+    let mut result = HashMap::new();
+    for line in contents.lines() {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() != 2 {
+            bail!("Invalid DB format")
+        }
+        let _ = result.insert(String::from(parts[0]), String::from(parts[1]));
+    }
+    Ok(result)
+}
+
+fn save_db(db: &HashMap<String, String>) -> Result<()> {
+    let mut file = File::create(utils::app_config()?.join(USERS_DB))?;
+    for (name, user) in db {
+        writeln!(file, "{name} {user}")?;
+    }
+    Ok(())
+}
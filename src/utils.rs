@@ -1,11 +1,18 @@
-use std::{borrow::Borrow, fs, path::PathBuf, process::Command};
+use std::{
+    borrow::Borrow,
+    fmt::Write as _,
+    fs,
+    io::{IsTerminal as _, Read},
+    path::PathBuf,
+    process::Command,
+};
 
 use anyhow::{Result, bail};
 use command_ext::CommandExt as _;
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use sha2::{Digest as _, Sha256};
 
-use crate::CommandExt as _;
-
-pub(crate) const PI_CONFIG: &str = "/home/pi/.pi";
+use crate::{CommandExt as _, users};
 
 pub(crate) enum Prompt {
     Yes,
@@ -41,10 +48,15 @@ pub(crate) fn app_config() -> Result<PathBuf> {
     Ok(path)
 }
 
+/// The directory used for staging files on the pi
+pub(crate) fn pi_config(name: &str) -> Result<String> {
+    Ok(format!("/home/{}/.pi", users::get(name)?))
+}
+
 pub(crate) fn ensure_pi_config(name: &str) -> Result<()> {
     Command::new("mkdir")
         .arg("-p")
-        .arg(PI_CONFIG)
+        .arg(pi_config(name)?)
         .run_on_pi(name)?
         .check_status()?;
     Ok(())
@@ -56,6 +68,65 @@ pub(crate) fn read_line() -> Result<String> {
     Ok(String::from(buf.trim()))
 }
 
+/// Whether there's someone at stdin to answer prompts
+pub(crate) fn interactive() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// Read a line without echoing it, keeping any surrounding whitespace
+pub(crate) fn read_password() -> Result<String> {
+    let stdin = std::io::stdin();
+    let original = termios::tcgetattr(&stdin)?;
+    let mut hidden = original.clone();
+    hidden.local_flags.remove(LocalFlags::ECHO);
+    termios::tcsetattr(&stdin, SetArg::TCSANOW, &hidden)?;
+    let _restore = defer::defer(|| {
+        let _ = termios::tcsetattr(&stdin, SetArg::TCSANOW, &original);
+    });
+    let mut buf = String::new();
+    let _ = stdin.read_line(&mut buf)?;
+    // The newline wasn't echoed either
+    println!();
+    Ok(buf.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// `value` if given, otherwise prompt for it with `question`
+///
+/// Fails (mentioning `hint`, the way to provide the value up front) if stdin
+/// isn't a terminal
+pub(crate) fn ask(
+    value: Option<String>,
+    question: &str,
+    hint: &str,
+) -> Result<String> {
+    ask_with(value, question, hint, read_line)
+}
+
+/// [ask] without echoing the answer
+pub(crate) fn ask_secret(
+    value: Option<String>,
+    question: &str,
+    hint: &str,
+) -> Result<String> {
+    ask_with(value, question, hint, read_password)
+}
+
+fn ask_with(
+    value: Option<String>,
+    question: &str,
+    hint: &str,
+    read: fn() -> Result<String>,
+) -> Result<String> {
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    if !interactive() {
+        bail!("{question} not given and stdin isn't a terminal, use {hint}")
+    }
+    prompt!("{question}: ");
+    read()
+}
+
 pub(crate) fn read_prompt(default: impl Borrow<Prompt>) -> Result<Prompt> {
     let response = read_line()?
         .to_lowercase()
@@ -69,6 +140,44 @@ pub(crate) fn read_prompt(default: impl Borrow<Prompt>) -> Result<Prompt> {
     Ok(if is_yes { Prompt::Yes } else { Prompt::No })
 }
 
+/// Human readable size using binary prefixes
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Hex encoded SHA-256 of everything `reader` produces
+pub(crate) fn sha256(mut reader: impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, byte| {
+        let _ = write!(s, "{byte:02x}");
+        s
+    })
+}
+
 #[allow(clippy::cast_sign_loss)]
 #[allow(clippy::cast_possible_truncation)]
 pub(crate) fn truncate(x: i32) -> u8 {
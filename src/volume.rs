@@ -0,0 +1,834 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        self,
+        fs::{PermissionsExt as _, chown},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use fatfs::{Dir, FileSystem, FsOptions};
+
+use crate::mbr::Partition;
+
+/// How debugfs reports a path that doesn't exist, as opposed to other errors
+const NOT_FOUND: &str = "File not found by ext2_lookup";
+
+/// Permissions and ownership for things written to a [Volume]
+///
+/// Ignored by filesystems that can't represent them (FAT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mode {
+    pub(crate) perm: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+impl Mode {
+    pub(crate) const FILE: Self = Self::root(0o644);
+    pub(crate) const DIR: Self = Self::root(0o755);
+
+    pub(crate) const fn root(perm: u32) -> Self {
+        Self::owned(perm, 0, 0)
+    }
+
+    pub(crate) const fn owned(perm: u32, uid: u32, gid: u32) -> Self {
+        Self { perm, uid, gid }
+    }
+}
+
+/// A filesystem on one of the image's partitions
+///
+/// Paths are relative to the root of the partition and separated by `/`
+pub(crate) trait Volume {
+    /// The contents of the file at `path`, `None` if it doesn't exist
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Create or replace the file at `path`, the parent directory must exist
+    fn write(&mut self, path: &str, contents: &[u8], mode: Mode) -> Result<()>;
+
+    /// Create the directory at `path` and any missing parents, existing
+    /// directories are left alone
+    fn create_dir(&mut self, path: &str, mode: Mode) -> Result<()>;
+
+    /// Remove the file or symlink at `path`, returning whether it existed
+    fn remove(&mut self, path: &str) -> Result<bool>;
+
+    /// Create or replace the symlink at `path`, pointing to `target` as it
+    /// will be seen from the image
+    fn symlink(&mut self, path: &str, target: &str) -> Result<()>;
+
+    fn read_to_string(&mut self, path: &str) -> Result<Option<String>> {
+        self.read(path)?
+            .map(|contents| {
+                String::from_utf8(contents)
+                    .with_context(|| format!("{path} isn't valid UTF-8"))
+            })
+            .transpose()
+    }
+}
+
+/// A partition mounted somewhere on the local filesystem
+#[derive(Debug)]
+pub(crate) struct Mounted(pub(crate) PathBuf);
+
+impl Volume for Mounted {
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.0.join(path)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow!(e).context(format!("Failed to read {path}"))),
+        }
+    }
+
+    fn write(&mut self, path: &str, contents: &[u8], mode: Mode) -> Result<()> {
+        let full = self.0.join(path);
+        fs::write(&full, contents)
+            .with_context(|| format!("Failed to write {path}"))?;
+        set_mode(&full, mode)
+    }
+
+    fn create_dir(&mut self, path: &str, mode: Mode) -> Result<()> {
+        let mut full = self.0.clone();
+        for component in components(path) {
+            full.push(component);
+            if !full.is_dir() {
+                fs::create_dir(&full)
+                    .with_context(|| format!("Failed to create {path}"))?;
+                set_mode(&full, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<bool> {
+        match fs::remove_file(self.0.join(path)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => {
+                Err(anyhow!(e).context(format!("Failed to remove {path}")))
+            }
+        }
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        let _ = self.remove(path)?;
+        unix::fs::symlink(target, self.0.join(path))
+            .with_context(|| format!("Failed to link {path} to {target}"))
+    }
+}
+
+fn set_mode(path: &Path, mode: Mode) -> Result<()> {
+    fs::set_permissions(path, Permissions::from_mode(mode.perm))?;
+    // Permissions on the partition don't matter if we can't chown, but we
+    // can't write to a mounted partition without root anyway
+    chown(path, Some(mode.uid), Some(mode.gid))?;
+    Ok(())
+}
+
+/// What's been written to a [CopyOnWrite] volume, by path
+///
+/// Kept apart from the volume so the changes are still there the next time
+/// the partition is opened
+#[derive(Debug, Default)]
+pub(crate) struct Changes(BTreeMap<String, Changed>);
+
+#[derive(Debug)]
+enum Changed {
+    File(Vec<u8>),
+    Symlink,
+    Removed,
+}
+
+/// A [Volume] that reads through to `base` but keeps everything written to
+/// it in `changes`, leaving `base` as it was
+///
+/// Directories aren't kept, files can be written under any path
+pub(crate) struct CopyOnWrite<'a> {
+    pub(crate) base: &'a mut dyn Volume,
+    pub(crate) changes: &'a mut Changes,
+}
+
+impl Volume for CopyOnWrite<'_> {
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        match self.changes.0.get(&normalize(path)) {
+            Some(Changed::File(contents)) => Ok(Some(contents.clone())),
+            // Links written here aren't followed
+            Some(Changed::Symlink | Changed::Removed) => Ok(None),
+            None => self.base.read(path),
+        }
+    }
+
+    fn write(&mut self, path: &str, contents: &[u8], _: Mode) -> Result<()> {
+        let _ = self
+            .changes
+            .0
+            .insert(normalize(path), Changed::File(contents.to_vec()));
+        Ok(())
+    }
+
+    fn create_dir(&mut self, _: &str, _: Mode) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<bool> {
+        let existed = match self.changes.0.get(&normalize(path)) {
+            Some(Changed::File(_) | Changed::Symlink) => true,
+            Some(Changed::Removed) => false,
+            None => self.base.read(path)?.is_some(),
+        };
+        if existed {
+            let _ = self.changes.0.insert(normalize(path), Changed::Removed);
+        }
+        Ok(existed)
+    }
+
+    fn symlink(&mut self, path: &str, _: &str) -> Result<()> {
+        let _ = self.changes.0.insert(normalize(path), Changed::Symlink);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for CopyOnWrite<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CopyOnWrite")
+            .field("changes", &self.changes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `path` in one form, so the same file is always found under the same key
+fn normalize(path: &str) -> String {
+    components(path).collect::<Vec<_>>().join("/")
+}
+
+/// A FAT partition inside an image file, edited in place without mounting
+pub(crate) struct Fat(FileSystem<Slice>);
+
+impl Fat {
+    pub(crate) fn open(image: &Path, partition: Partition) -> Result<Self> {
+        Self::open_with(image, partition, true)
+    }
+
+    /// Open the partition without write access to the image, for looking at
+    /// it without any chance of changing it
+    pub(crate) fn open_read_only(
+        image: &Path,
+        partition: Partition,
+    ) -> Result<Self> {
+        Self::open_with(image, partition, false)
+    }
+
+    fn open_with(
+        image: &Path,
+        partition: Partition,
+        write: bool,
+    ) -> Result<Self> {
+        let slice = Slice::open(image, partition, write)?;
+        Ok(Self(
+            FileSystem::new(slice, FsOptions::new()).with_context(|| {
+                format!(
+                    "Partition {} of {} isn't a FAT filesystem",
+                    partition.number,
+                    image.display()
+                )
+            })?,
+        ))
+    }
+
+    /// Flush all changes back to the image
+    pub(crate) fn close(self) -> Result<()> {
+        self.0.unmount()?;
+        Ok(())
+    }
+
+    /// Copy every file on the partition into the local directory `dest`,
+    /// returning how many were copied
+    pub(crate) fn extract(&self, dest: &Path) -> Result<usize> {
+        extract_dir(&self.0.root_dir(), dest)
+    }
+}
+
+fn extract_dir(dir: &Dir<'_, Slice>, dest: &Path) -> Result<usize> {
+    fs::create_dir_all(dest)
+        .with_context(|| format!("Failed to create {}", dest.display()))?;
+    let mut copied = 0;
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = dest.join(&name);
+        if entry.is_dir() {
+            copied += extract_dir(&entry.to_dir(), &path)?;
+        } else {
+            let mut file = File::create(&path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?;
+            let _ = io::copy(&mut entry.to_file(), &mut file)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+impl Volume for Fat {
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        let mut file = match self.0.root_dir().open_file(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(
+                    anyhow!(e).context(format!("Failed to open {path}"))
+                );
+            }
+        };
+        let mut contents = Vec::new();
+        let _ = file.read_to_end(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    fn write(&mut self, path: &str, contents: &[u8], _: Mode) -> Result<()> {
+        let mut file = self
+            .0
+            .root_dir()
+            .create_file(path)
+            .with_context(|| format!("Failed to create {path}"))?;
+        file.truncate()?;
+        file.write_all(contents)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &str, _: Mode) -> Result<()> {
+        let mut dir = self.0.root_dir();
+        for component in components(path) {
+            dir = dir
+                .create_dir(component)
+                .with_context(|| format!("Failed to create {path}"))?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<bool> {
+        match self.0.root_dir().remove(path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => {
+                Err(anyhow!(e).context(format!("Failed to remove {path}")))
+            }
+        }
+    }
+
+    fn symlink(&mut self, path: &str, _: &str) -> Result<()> {
+        bail!("Can't create {path}, FAT doesn't have symlinks")
+    }
+}
+
+impl std::fmt::Debug for Fat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Fat").finish_non_exhaustive()
+    }
+}
+
+/// An ext2/3/4 partition inside an image file, edited with debugfs so it
+/// doesn't need to be mounted
+#[derive(Debug)]
+pub(crate) struct Ext {
+    // debugfs accepts `path?offset=N` to open a filesystem part way into a
+    // file
+    spec: String,
+    tempdir: tempfile::TempDir,
+}
+
+impl Ext {
+    pub(crate) fn open(image: &Path, partition: Partition) -> Result<Self> {
+        let image = image.to_str().ok_or_else(|| {
+            anyhow!("debugfs can't open {} (not UTF-8)", image.display())
+        })?;
+        let ext = Self {
+            spec: format!("{image}?offset={}", partition.start),
+            tempdir: tempfile::tempdir()?,
+        };
+        let _ = ext.debugfs(false, &[String::from("stats -h")])?;
+        Ok(ext)
+    }
+
+    /// Copy the whole filesystem into the local directory `dest`, keeping
+    /// ownership (which needs root) and permissions
+    pub(crate) fn extract(&self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)
+            .with_context(|| format!("Failed to create {}", dest.display()))?;
+        let _ = self.debugfs(false, &[format!("rdump / {}", local(dest)?)])?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let (_, stderr) =
+            self.run(false, &[format!("stat {}", quote(path)?)])?;
+        if stderr.is_empty() {
+            Ok(true)
+        } else if stderr.trim_end().ends_with(NOT_FOUND) {
+            Ok(false)
+        } else {
+            bail!("debugfs failed: {stderr}")
+        }
+    }
+
+    /// Run debugfs commands, failing if any of them print an error
+    fn debugfs(&self, write: bool, commands: &[String]) -> Result<String> {
+        let (stdout, stderr) = self.run(write, commands)?;
+        if !stderr.is_empty() {
+            bail!("debugfs failed: {stderr}")
+        }
+        Ok(stdout)
+    }
+
+    fn run(
+        &self,
+        write: bool,
+        commands: &[String],
+    ) -> Result<(String, String)> {
+        let mut debugfs = Command::new("debugfs");
+        if write {
+            let _ = debugfs.arg("-w");
+        }
+        let mut child = debugfs
+            .args(["-f", "-"])
+            .arg(&self.spec)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run debugfs (is e2fsprogs installed?)")?;
+        {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            for command in commands {
+                writeln!(stdin, "{command}")?;
+            }
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!("debugfs exited with {}", output.status)
+        }
+        // debugfs always prints its version banner to stderr, and doesn't
+        // reflect errors in its exit status
+        let stderr = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter(|line| !line.starts_with("debugfs "))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok((String::from_utf8_lossy(&output.stdout).into_owned(), stderr))
+    }
+}
+
+impl Volume for Ext {
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        if !self.exists(path)? {
+            return Ok(None);
+        }
+        let dest = self.tempdir.path().join("read");
+        let _ = self.debugfs(
+            false,
+            &[format!("dump {} {}", quote(path)?, local(&dest)?)],
+        )?;
+        let contents = fs::read(&dest)?;
+        fs::remove_file(dest)?;
+        Ok(Some(contents))
+    }
+
+    fn write(&mut self, path: &str, contents: &[u8], mode: Mode) -> Result<()> {
+        let quoted = quote(path)?;
+        let src = self.tempdir.path().join("write");
+        fs::write(&src, contents)?;
+        let mut commands = Vec::new();
+        if self.exists(path)? {
+            commands.push(format!("rm {quoted}"));
+        }
+        commands.push(format!("write {} {quoted}", local(&src)?));
+        commands.extend(set_inode(&quoted, 0o100_000 | mode.perm, mode));
+        let _ = self.debugfs(true, &commands)?;
+        fs::remove_file(src)?;
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &str, mode: Mode) -> Result<()> {
+        let mut full = String::new();
+        for component in components(path) {
+            if !full.is_empty() {
+                full.push('/');
+            }
+            full.push_str(component);
+            if !self.exists(&full)? {
+                let quoted = quote(&full)?;
+                let mut commands = vec![format!("mkdir {quoted}")];
+                commands.extend(set_inode(
+                    &quoted,
+                    0o040_000 | mode.perm,
+                    mode,
+                ));
+                let _ = self.debugfs(true, &commands)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<bool> {
+        if !self.exists(path)? {
+            return Ok(false);
+        }
+        let _ = self.debugfs(true, &[format!("rm {}", quote(path)?)])?;
+        Ok(true)
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        let command = format!("symlink {} {}", quote(path)?, quoted(target)?);
+        let _ = self.remove(path)?;
+        let _ = self.debugfs(true, &[command])?;
+        Ok(())
+    }
+}
+
+/// Commands setting the type, permissions and owner of `path`, already
+/// quoted
+fn set_inode(path: &str, mode: u32, owner: Mode) -> [String; 3] {
+    [
+        format!("set_inode_field {path} mode 0{mode:o}"),
+        format!("set_inode_field {path} uid {}", owner.uid),
+        format!("set_inode_field {path} gid {}", owner.gid),
+    ]
+}
+
+/// A path on the partition, made absolute and quoted for a debugfs command
+fn quote(path: &str) -> Result<String> {
+    quoted(&format!("/{}", path.trim_start_matches('/')))
+}
+
+/// A local path quoted for a debugfs command
+fn local(path: &Path) -> Result<String> {
+    quoted(path.to_str().ok_or_else(|| {
+        anyhow!("debugfs can't use {} (not UTF-8)", path.display())
+    })?)
+}
+
+/// `arg` in double quotes, as debugfs splits its commands
+///
+/// debugfs reads a command per line and has no way of escaping a quote, so
+/// anything with either can't be passed to it (and could otherwise run
+/// commands of its own)
+fn quoted(arg: &str) -> Result<String> {
+    if arg.contains(|c: char| c == '"' || c.is_control()) {
+        bail!(
+            "debugfs can't handle {arg:?}, it has a quote or control character"
+        )
+    }
+    Ok(format!("\"{arg}\""))
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+/// Window onto a single partition of an image file
+struct Slice {
+    file: File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Slice {
+    fn open(image: &Path, partition: Partition, write: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(image)
+            .with_context(|| format!("Failed to open {}", image.display()))?;
+        Ok(Self {
+            file,
+            start: partition.start,
+            len: partition.size,
+            pos: 0,
+        })
+    }
+
+    /// How much of a `want` byte read or write fits in the partition
+    fn remaining(&self, want: usize) -> usize {
+        let remaining = self.len.saturating_sub(self.pos);
+        want.min(usize::try_from(remaining).unwrap_or(usize::MAX))
+    }
+}
+
+impl Read for Slice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.remaining(buf.len());
+        let _ = self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.file.read(&mut buf[..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Slice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.remaining(buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::WriteZero,
+                "write past the end of the partition",
+            ));
+        }
+        let _ = self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let written = self.file.write(&buf[..len])?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Slice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "seek before the start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fatfs::FormatVolumeOptions;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::mbr;
+
+    /// A 3 MiB image with a 2 MiB FAT partition, 1 MiB in
+    fn fat_image() -> (NamedTempFile, Partition) {
+        let image = NamedTempFile::new().unwrap();
+        image.as_file().set_len(3 << 20).unwrap();
+        image
+            .as_file()
+            .write_all(&mbr::table(&[(mbr::FAT16_LBA, 2048, 4096)]))
+            .unwrap();
+        let partition = mbr::partition(image.path(), 1).unwrap();
+        fatfs::format_volume(
+            Slice::open(image.path(), partition, true).unwrap(),
+            FormatVolumeOptions::new(),
+        )
+        .unwrap();
+        (image, partition)
+    }
+
+    /// A 4 MiB ext4 filesystem, as if it were a partition starting at the
+    /// beginning of the image
+    fn ext_image() -> (NamedTempFile, Partition) {
+        let image = NamedTempFile::new().unwrap();
+        image.as_file().set_len(4 << 20).unwrap();
+        let status = Command::new("mke2fs")
+            .args(["-q", "-F", "-t", "ext4"])
+            .arg(image.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let partition = Partition {
+            number: 2,
+            kind: mbr::LINUX,
+            start: 0,
+            size: 4 << 20,
+        };
+        (image, partition)
+    }
+
+    #[test]
+    fn ext_write_then_read() {
+        let (image, partition) = ext_image();
+        let mut ext = Ext::open(image.path(), partition).unwrap();
+        assert_eq!(ext.read("etc/hostname").unwrap(), None);
+        ext.create_dir("etc/ssh", Mode::DIR).unwrap();
+        ext.write("etc/hostname", b"raspberrypi", Mode::FILE)
+            .unwrap();
+        ext.write("etc/hostname", b"sensor", Mode::FILE).unwrap();
+        assert_eq!(
+            ext.read_to_string("/etc/hostname").unwrap().as_deref(),
+            Some("sensor")
+        );
+        assert!(ext.remove("etc/hostname").unwrap());
+        assert!(!ext.remove("etc/hostname").unwrap());
+    }
+
+    #[test]
+    fn ext_exists_only_hides_missing_files() {
+        let (image, partition) = ext_image();
+        let mut ext = Ext::open(image.path(), partition).unwrap();
+        ext.write("file", b"", Mode::FILE).unwrap();
+        assert!(ext.exists("file").unwrap());
+        assert!(!ext.exists("missing").unwrap());
+        assert!(!ext.exists("missing/file").unwrap());
+        assert!(ext.exists("file/file").is_err());
+    }
+
+    #[test]
+    fn ext_rejects_paths_debugfs_cant_quote() {
+        let (image, partition) = ext_image();
+        let mut ext = Ext::open(image.path(), partition).unwrap();
+        ext.write("keep", b"kept", Mode::FILE).unwrap();
+        for path in ["a\" \"/keep", "a\nrm /keep", "a\rb"] {
+            assert!(ext.write(path, b"", Mode::FILE).is_err());
+            assert!(ext.create_dir(path, Mode::DIR).is_err());
+            assert!(ext.remove(path).is_err());
+            assert!(ext.symlink(path, "keep").is_err());
+            assert!(ext.symlink("link", path).is_err());
+        }
+        assert_eq!(ext.read("keep").unwrap(), Some(b"kept".to_vec()));
+        assert_eq!(ext.read("link").unwrap(), None);
+    }
+
+    #[test]
+    fn fat_write_then_read() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        assert_eq!(fat.read("cmdline.txt").unwrap(), None);
+        fat.write("cmdline.txt", b"console=tty1", Mode::FILE)
+            .unwrap();
+        fat.write("cmdline.txt", b"quiet", Mode::FILE).unwrap();
+        assert_eq!(
+            fat.read_to_string("cmdline.txt").unwrap().as_deref(),
+            Some("quiet")
+        );
+        fat.close().unwrap();
+
+        // Changes are in the image, not just the open filesystem
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        assert_eq!(fat.read("cmdline.txt").unwrap(), Some(b"quiet".to_vec()));
+    }
+
+    #[test]
+    fn fat_create_dir() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        fat.create_dir("overlays/extra", Mode::DIR).unwrap();
+        // Existing directories are left alone
+        fat.create_dir("/overlays/", Mode::DIR).unwrap();
+        fat.write("overlays/extra/a.dtbo", b"a", Mode::FILE)
+            .unwrap();
+        assert_eq!(
+            fat.read("overlays/extra/a.dtbo").unwrap(),
+            Some(b"a".to_vec())
+        );
+    }
+
+    #[test]
+    fn fat_remove() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        fat.write("ssh", b"", Mode::FILE).unwrap();
+        assert!(fat.remove("ssh").unwrap());
+        assert!(!fat.remove("ssh").unwrap());
+        assert_eq!(fat.read("ssh").unwrap(), None);
+    }
+
+    #[test]
+    fn fat_has_no_symlinks() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        assert!(fat.symlink("link", "target").is_err());
+    }
+
+    #[test]
+    fn fat_stays_in_its_partition() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        fat.write("big", &vec![0xff; 1 << 20], Mode::FILE).unwrap();
+        fat.close().unwrap();
+
+        let contents = fs::read(image.path()).unwrap();
+        let end = usize::try_from(partition.start + partition.size).unwrap();
+        assert!(contents[end..].iter().all(|byte| *byte == 0));
+        assert_eq!(mbr::read(image.path()).unwrap(), [partition]);
+    }
+
+    #[test]
+    fn fat_extract() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        fat.create_dir("overlays", Mode::DIR).unwrap();
+        fat.write("config.txt", b"arm_64bit=1\n", Mode::FILE)
+            .unwrap();
+        fat.write("overlays/a.dtbo", b"a", Mode::FILE).unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        assert_eq!(fat.extract(dest.path()).unwrap(), 2);
+        assert_eq!(
+            fs::read(dest.path().join("overlays/a.dtbo")).unwrap(),
+            b"a"
+        );
+    }
+
+    #[test]
+    fn copy_on_write_leaves_the_base_alone() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open(image.path(), partition).unwrap();
+        fat.write("config.txt", b"arm_64bit=1\n", Mode::FILE)
+            .unwrap();
+        fat.write("ssh", b"", Mode::FILE).unwrap();
+        fat.close().unwrap();
+        let before = fs::read(image.path()).unwrap();
+
+        let mut changes = Changes::default();
+        {
+            let mut fat = Fat::open_read_only(image.path(), partition).unwrap();
+            let mut cow = CopyOnWrite {
+                base: &mut fat,
+                changes: &mut changes,
+            };
+            cow.create_dir("overlays", Mode::DIR).unwrap();
+            cow.write("/overlays/a.dtbo", b"a", Mode::FILE).unwrap();
+            cow.write("config.txt", b"dtparam=audio=on\n", Mode::FILE)
+                .unwrap();
+            assert!(cow.remove("ssh").unwrap());
+            assert!(!cow.remove("ssh").unwrap());
+            assert!(!cow.remove("missing").unwrap());
+            cow.symlink("link", "ssh").unwrap();
+            assert_eq!(
+                cow.read("overlays/a.dtbo").unwrap(),
+                Some(b"a".to_vec())
+            );
+            assert_eq!(cow.read("ssh").unwrap(), None);
+            assert!(cow.remove("link").unwrap());
+        }
+
+        // Opened again, the partition still has the changes on top
+        let mut fat = Fat::open_read_only(image.path(), partition).unwrap();
+        let mut cow = CopyOnWrite {
+            base: &mut fat,
+            changes: &mut changes,
+        };
+        assert_eq!(
+            cow.read_to_string("config.txt").unwrap().as_deref(),
+            Some("dtparam=audio=on\n")
+        );
+        drop(fat);
+        assert_eq!(fs::read(image.path()).unwrap(), before);
+    }
+
+    #[test]
+    fn read_only_fat_can_not_write() {
+        let (image, partition) = fat_image();
+        let mut fat = Fat::open_read_only(image.path(), partition).unwrap();
+        assert!(fat.write("ssh", b"", Mode::FILE).is_err());
+    }
+}
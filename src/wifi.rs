@@ -0,0 +1,432 @@
+use std::{
+    fmt::{self, Write as _},
+    str::FromStr,
+};
+
+use anyhow::{Result, anyhow, bail};
+use pbkdf2::pbkdf2_hmac;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{static_ip::StaticIp, utils};
+
+const MAX_SSID_LEN: usize = 32;
+const PSK_LEN: usize = 32;
+// Fixed by IEEE 802.11i
+const PSK_ITERATIONS: u32 = 4096;
+const HEX_PREFIX: &str = "hex:";
+pub(crate) const DEFAULT_COUNTRY: &str = "GB";
+pub(crate) const SYSTEM_CONNECTIONS: &str =
+    "etc/NetworkManager/system-connections";
+
+/// A wifi network name
+///
+/// SSIDs are arbitrary bytes, so ones that aren't valid UTF-8 (or are awkward
+/// to type) can be given in hex with a `hex:` prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Ssid(Vec<u8>);
+
+impl Ssid {
+    pub(crate) fn parse(ssid: &str) -> Result<Self> {
+        let bytes = match ssid.strip_prefix(HEX_PREFIX) {
+            Some(hex) => {
+                unhex(hex).ok_or_else(|| anyhow!("Invalid hex SSID {ssid}"))?
+            }
+            None => ssid.as_bytes().to_vec(),
+        };
+        if bytes.is_empty() || bytes.len() > MAX_SSID_LEN {
+            bail!("SSIDs must be 1 to {MAX_SSID_LEN} bytes long")
+        }
+        Ok(Self(bytes))
+    }
+
+    /// The SSID as text, None if it isn't valid UTF-8
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    /// The SSID as a wpa_supplicant.conf value
+    ///
+    /// wpa_supplicant has no escapes inside quoted strings, so anything that
+    /// isn't plain printable ASCII is written as unquoted hex instead
+    pub(crate) fn to_conf(&self) -> String {
+        if self
+            .0
+            .iter()
+            .all(|byte| (b' '..=b'~').contains(byte) && *byte != b'"')
+        {
+            format!("\"{}\"", String::from_utf8_lossy(&self.0))
+        } else {
+            utils::hex(&self.0)
+        }
+    }
+
+    /// The SSID as a NetworkManager keyfile value
+    ///
+    /// Keyfiles have their own escaping rules, so anything beyond plain
+    /// words is written as a list of byte values instead
+    fn to_keyfile(&self) -> String {
+        let simple =
+            self.0.iter().all(|byte| {
+                byte.is_ascii_alphanumeric() || b" -_.".contains(byte)
+            }) && !self.0.starts_with(b" ")
+                && !self.0.ends_with(b" ");
+        if simple {
+            String::from_utf8_lossy(&self.0).into_owned()
+        } else {
+            self.0.iter().fold(String::new(), |mut s, byte| {
+                let _ = write!(s, "{byte};");
+                s
+            })
+        }
+    }
+
+    /// A name for the connection that's safe to use as a file name
+    fn connection_id(&self) -> String {
+        String::from_utf8_lossy(&self.0)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+/// Shown in the form [Ssid::parse] reads back, in hex if it isn't text or
+/// the text would be taken for hex
+impl fmt::Display for Ssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_str() {
+            Some(ssid) if !ssid.starts_with(HEX_PREFIX) => write!(f, "{ssid}"),
+            _ => write!(f, "{HEX_PREFIX}{}", utils::hex(&self.0)),
+        }
+    }
+}
+
+/// A WPA pre-shared key, what `wpa_passphrase` would put on the card in
+/// place of the passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Psk([u8; PSK_LEN]);
+
+impl Psk {
+    /// Derive the PSK for `ssid` from a passphrase, or take a PSK that's
+    /// already been derived as 64 hex digits
+    pub(crate) fn new(ssid: &Ssid, secret: &str) -> Result<Self> {
+        if secret.len() == PSK_LEN * 2
+            && let Some(psk) = unhex(secret)
+        {
+            return Ok(Self(psk.try_into().expect("length checked above")));
+        }
+        if !(8..=63).contains(&secret.len())
+            || !secret.bytes().all(|byte| (b' '..=b'~').contains(&byte))
+        {
+            bail!("Wifi passphrases must be 8 to 63 printable ASCII characters")
+        }
+        let mut psk = [0; PSK_LEN];
+        pbkdf2_hmac::<Sha1>(
+            secret.as_bytes(),
+            &ssid.0,
+            PSK_ITERATIONS,
+            &mut psk,
+        );
+        Ok(Self(psk))
+    }
+}
+
+impl fmt::Display for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", utils::hex(&self.0))
+    }
+}
+
+/// How a network authenticates
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Security {
+    /// WPA2 personal
+    #[default]
+    Wpa2,
+    /// WPA3 personal
+    Sae,
+    Open,
+}
+
+impl FromStr for Security {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "wpa2" => Ok(Self::Wpa2),
+            "sae" | "wpa3" => Ok(Self::Sae),
+            "open" => Ok(Self::Open),
+            _ => Err(format!(
+                "Unknown security {s} (expected wpa2, sae or open)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Wpa2 => write!(f, "wpa2"),
+            Self::Sae => write!(f, "sae"),
+            Self::Open => write!(f, "open"),
+        }
+    }
+}
+
+/// The secret for a network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Key {
+    Open,
+    Wpa2(Psk),
+    /// SAE runs its handshake on the passphrase itself so, unlike WPA2, it
+    /// has to be stored as is
+    Sae(String),
+}
+
+impl Key {
+    /// The key for `ssid` using `security`, `secret` being the passphrase
+    /// (or for WPA2 optionally the derived PSK)
+    pub(crate) fn new(
+        security: Security,
+        ssid: &Ssid,
+        secret: Option<&str>,
+    ) -> Result<Self> {
+        match (security, secret) {
+            (Security::Open, None) => Ok(Self::Open),
+            (Security::Open, Some(_)) => {
+                bail!("Open networks don't have a password")
+            }
+            (_, None) => bail!("{ssid} needs a password"),
+            (Security::Wpa2, Some(secret)) => {
+                Ok(Self::Wpa2(Psk::new(ssid, secret)?))
+            }
+            (Security::Sae, Some(secret)) => {
+                if secret.is_empty()
+                    || !secret.bytes().all(|byte| (b' '..=b'~').contains(&byte))
+                {
+                    bail!("SAE passwords must be printable ASCII")
+                }
+                Ok(Self::Sae(secret.to_owned()))
+            }
+        }
+    }
+
+    pub(crate) fn security(&self) -> Security {
+        match self {
+            Self::Open => Security::Open,
+            Self::Wpa2(_) => Security::Wpa2,
+            Self::Sae(_) => Security::Sae,
+        }
+    }
+
+    /// The stored form of the secret, which [Key::new] accepts back
+    pub(crate) fn secret(&self) -> Option<String> {
+        match self {
+            Self::Open => None,
+            Self::Wpa2(psk) => Some(psk.to_string()),
+            Self::Sae(password) => Some(password.clone()),
+        }
+    }
+}
+
+/// A network for the Pi to join
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Network {
+    pub(crate) ssid: Ssid,
+    pub(crate) key: Key,
+    /// Higher priorities are preferred when several networks are in range
+    pub(crate) priority: i32,
+    /// The network doesn't broadcast its SSID so has to be probed for
+    pub(crate) hidden: bool,
+}
+
+/// A two letter ISO 3166 country code, which decides the channels and power
+/// levels the radio is allowed to use
+pub(crate) fn country(country: &str) -> Result<String> {
+    if country.len() != 2
+        || !country.bytes().all(|byte| byte.is_ascii_alphabetic())
+    {
+        bail!("Invalid country code {country} (expected e.g. GB or US)")
+    }
+    Ok(country.to_ascii_uppercase())
+}
+
+/// A wpa_supplicant.conf connecting to any of `networks`
+pub(crate) fn wpa_supplicant(country: &str, networks: &[Network]) -> String {
+    let mut conf = format!(
+        "country={country}\n\
+         ctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\n\
+         update_config=1\n",
+    );
+    for network in networks {
+        let _ = writeln!(conf);
+        let _ = writeln!(conf, "network={{");
+        let _ = writeln!(conf, "    ssid={}", network.ssid.to_conf());
+        if network.hidden {
+            let _ = writeln!(conf, "    scan_ssid=1");
+        }
+        match &network.key {
+            Key::Open => {
+                let _ = writeln!(conf, "    key_mgmt=NONE");
+            }
+            Key::Wpa2(psk) => {
+                let _ = writeln!(conf, "    psk={psk}");
+            }
+            Key::Sae(password) => {
+                // The quoted value runs to the last quote on the line so
+                // quotes in the password don't need escaping
+                let _ = writeln!(conf, "    key_mgmt=SAE");
+                let _ = writeln!(conf, "    sae_password=\"{password}\"");
+                let _ = writeln!(conf, "    ieee80211w=2");
+            }
+        }
+        if network.priority != 0 {
+            let _ = writeln!(conf, "    priority={}", network.priority);
+        }
+        let _ = writeln!(conf, "}}");
+    }
+    conf
+}
+
+/// NetworkManager connection profiles for `networks`, as (file name,
+/// contents) pairs to go in [SYSTEM_CONNECTIONS]
+///
+/// The UUIDs are left for NetworkManager to derive from the file names
+pub(crate) fn network_manager(
+    networks: &[Network],
+    static_ip: Option<&StaticIp>,
+) -> Vec<(String, String)> {
+    let mut connections: Vec<(String, String)> = Vec::new();
+    for network in networks {
+        let mut id = network.ssid.connection_id();
+        if connections
+            .iter()
+            .any(|(file, _)| file.starts_with(&format!("{id}.")))
+        {
+            id = format!("{id}-{}", connections.len());
+        }
+
+        let mut conf = String::new();
+        let _ = writeln!(conf, "[connection]");
+        let _ = writeln!(conf, "id={id}");
+        let _ = writeln!(conf, "type=wifi");
+        let _ = writeln!(conf, "autoconnect=true");
+        if network.priority != 0 {
+            let _ = writeln!(conf, "autoconnect-priority={}", network.priority);
+        }
+        let _ = writeln!(conf);
+        let _ = writeln!(conf, "[wifi]");
+        let _ = writeln!(conf, "mode=infrastructure");
+        let _ = writeln!(conf, "ssid={}", network.ssid.to_keyfile());
+        if network.hidden {
+            let _ = writeln!(conf, "hidden=true");
+        }
+        match &network.key {
+            Key::Open => (),
+            Key::Wpa2(psk) => {
+                let _ = writeln!(conf);
+                let _ = writeln!(conf, "[wifi-security]");
+                let _ = writeln!(conf, "key-mgmt=wpa-psk");
+                let _ = writeln!(conf, "psk={psk}");
+            }
+            Key::Sae(password) => {
+                let _ = writeln!(conf);
+                let _ = writeln!(conf, "[wifi-security]");
+                let _ = writeln!(conf, "key-mgmt=sae");
+                let _ = writeln!(conf, "psk={}", keyfile_escape(password));
+            }
+        }
+        conf.push_str(&StaticIp::keyfile(static_ip));
+
+        connections.push((format!("{id}.nmconnection"), conf));
+    }
+    connections
+}
+
+/// Escape a (printable ASCII) string for a keyfile value
+fn keyfile_escape(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\");
+    match escaped.strip_prefix(' ') {
+        Some(rest) => format!("\\s{rest}"),
+        None => escaped,
+    }
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2)
+        || !hex.bytes().all(|byte| byte.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vectors from IEEE 802.11i-2004 H.4
+    #[test]
+    fn psk_known_answers() {
+        for (passphrase, ssid, psk) in [
+            (
+                "password",
+                "IEEE",
+                "f42c6fc52df0ebef9ebb4b90b38a5f902e83fe1b135a70e23aed762e9710a12e",
+            ),
+            (
+                "ThisIsAPassword",
+                "ThisIsASSID",
+                "0dc0d6eb90555ed6419756b9a15ec3e3209b63df707dd508d14581f8982721af",
+            ),
+            (
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                "ZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZ",
+                "becb93866bb8c3832cb777c2f559807c8c59afcb6eae734885001300a981cc62",
+            ),
+        ] {
+            let ssid = Ssid::parse(ssid).unwrap();
+            assert_eq!(Psk::new(&ssid, passphrase).unwrap().to_string(), psk);
+        }
+    }
+
+    #[test]
+    fn ssid_round_trips() {
+        for ssid in ["home", "hex:cafe", "hex:68657a3a636166"] {
+            let parsed = Ssid::parse(ssid).unwrap();
+            assert_eq!(Ssid::parse(&parsed.to_string()).unwrap(), parsed);
+        }
+        let text = Ssid::parse("hex:6865783a63616665").unwrap();
+        assert_eq!(text.as_str(), Some("hex:cafe"));
+        assert_eq!(text.to_string(), "hex:6865783a63616665");
+        assert_eq!(Ssid::parse("hex:ff00").unwrap().to_string(), "hex:ff00");
+    }
+
+    #[test]
+    fn psk_taken_as_is() {
+        let psk =
+            "f42c6fc52df0ebef9ebb4b90b38a5f902e83fe1b135a70e23aed762e9710a12e";
+        let ssid = Ssid::parse("anything").unwrap();
+        assert_eq!(Psk::new(&ssid, psk).unwrap().to_string(), psk);
+    }
+
+    #[test]
+    fn psk_rejects_bad_passphrases() {
+        let ssid = Ssid::parse("IEEE").unwrap();
+        assert!(Psk::new(&ssid, "short").is_err());
+        assert!(Psk::new(&ssid, &"x".repeat(64)).is_err());
+        assert!(Psk::new(&ssid, "pass\u{e9}word").is_err());
+    }
+}